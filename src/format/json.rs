@@ -0,0 +1,69 @@
+use super::Format;
+use crate::{diagnostic::Diagnostic, keymap::KeyMap};
+
+/// The fully resolved keymap as JSON,
+/// which can be loaded again with [`KeyMap::from_json`].
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct JSON;
+
+impl Format for JSON {
+    fn format(&self, keymap: &KeyMap) -> String {
+        serde_json::to_string_pretty(keymap).unwrap()
+    }
+
+    /// Every key can be represented.
+    fn check(&self, _keymap: &KeyMap) -> Vec<Diagnostic> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{QMK, ZMK};
+
+    const LAYOUT: &str = "\
+BASE
+
+A B C D
+E F G H
+
+holds:
+  A: Shift
+  B: Layer(NAV)
+combos:
+  [C, D]: Esc
+  [G, H]: [X, Layer(NAV)]
+shifts:
+  E: Ctrl(E)
+defs:
+  C: Ctrl(C)
+
+---
+
+NAV
+
+n t s l
+⬚ ∅ ⏷ ⏶
+
+defs:
+  n: Enable(BASE)
+  t: Toggle(BASE)
+  s: OneShot(Shift)
+  l: OneShot(BASE)
+encoders:
+  0: { ccw: ⏷, cw: ⏶ }
+";
+
+    /// A keymap loaded from its JSON generates the same as the layout it came from.
+    #[test]
+    fn round_trip() {
+        let keymap = KeyMap::parse(LAYOUT).unwrap();
+        let loaded = KeyMap::from_json(&JSON.format(&keymap)).unwrap();
+        let targets: [&dyn Format; 2] = [&QMK::default(), &ZMK::default()];
+        for target in targets {
+            assert_eq!(target.format(&loaded), target.format(&keymap));
+        }
+    }
+}
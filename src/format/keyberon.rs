@@ -0,0 +1,361 @@
+use genco::prelude::*;
+
+use super::{
+    Format, check_matrix, matrix_positions, parse_matrix, send_mods, transparent_outputs,
+    typed_with,
+};
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::KeyMap,
+    keys::{HoldKey, Key, KeyDef, Modifier, TapKey},
+};
+
+/// A Rust module with the `LAYERS` for keyberon-based firmware,
+/// and the `CHORDS` for its chording module if there are combos.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Keyberon {
+    /// The hold-tap `timeout`, in ticks (usually ms).
+    pub tapping_term: u16,
+
+    /// See [`Vial::matrix`](super::Vial::matrix).
+    pub matrix: Vec<(usize, usize)>,
+}
+impl Default for Keyberon {
+    fn default() -> Self {
+        Self {
+            tapping_term: 200,
+            matrix: vec![],
+        }
+    }
+}
+impl Keyberon {
+    /// Options from the `KEYBERON_TAPPING_TERM` and `KEYBERON_MATRIX`
+    /// (space-separated `row,column` pairs) environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            tapping_term: std::env::var("KEYBERON_TAPPING_TERM")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.tapping_term),
+            matrix: std::env::var("KEYBERON_MATRIX")
+                .map(|v| parse_matrix(&v))
+                .unwrap_or(default.matrix),
+        }
+    }
+}
+
+impl Format for Keyberon {
+    fn format(&self, keymap: &KeyMap) -> String {
+        let positions = matrix_positions(&self.matrix, keymap);
+        let matrix_rows = positions.iter().map(|(row, _)| row + 1).max().unwrap_or(0);
+        let matrix_cols = positions.iter().map(|(_, col)| col + 1).max().unwrap_or(0);
+
+        // Chords are sent as the keys of an extra row, one per combo.
+        let combos: Vec<_> = keymap.combos().collect();
+        let rows = matrix_rows + usize::from(!combos.is_empty());
+        let cols = matrix_cols.max(combos.len());
+
+        let layers: Vec<_> = keymap
+            .layers
+            .iter()
+            .map(|layer| {
+                // Matrix positions without a key do nothing.
+                let mut matrix = vec![vec!["NoOp".to_string(); cols]; rows];
+                for (key, (row, col)) in layer.layout.iter().zip(&positions) {
+                    matrix[*row][*col] = self.kd(key);
+                }
+                // Combos that aren't active on the layer do nothing.
+                for (i, combo) in combos.iter().enumerate() {
+                    if combo.layers.contains(&layer.name) {
+                        matrix[matrix_rows][i] = self.kd(&combo.output);
+                    }
+                }
+                (&layer.name, matrix)
+            })
+            .collect();
+
+        let chords: Vec<_> = combos
+            .iter()
+            .enumerate()
+            .map(|(i, combo)| {
+                let inputs: Vec<_> = combo
+                    .inputs
+                    .iter()
+                    .filter_map(|position| positions.get(*position))
+                    .map(|(row, col)| format!("({row}, {col})"))
+                    .collect();
+                format!("(({matrix_rows}, {i}), &[{}])", inputs.join(", "))
+            })
+            .collect();
+
+        let layer_count = keymap.layers.len();
+        // Line breaks are explicit, so that comments don't run into the code.
+        let tokens: rust::Tokens = quote! {
+            $("//! Generated by kbl.")$['\n']
+            use keyberon::action::{Action::*, HoldTapAction, HoldTapConfig, k, l, m};$['\r']
+            $(if !chords.is_empty() {
+                use keyberon::chording::ChordDef;$['\r']
+            })
+            use keyberon::key_code::KeyCode::*;$['\n']
+
+            pub const ROWS: usize = $rows;$['\r']
+            pub const COLS: usize = $cols;$['\n']
+
+            $(for (i, layer) in keymap.layers.iter().enumerate() =>
+                pub const $(&layer.name): usize = $i;$['\r']
+            )
+            $['\n']
+            pub type Layers = keyberon::layout::Layers<COLS, ROWS, $layer_count>;$['\n']
+
+            pub static LAYERS: Layers = [$['\r']
+                $(for (name, matrix) in layers =>
+                    $(format!("// {name}"))$['\r']
+                    [$['\r']
+                        $(for row in matrix =>
+                            [$(for action in row join(, ) => $action)],$['\r']
+                        )
+                    ],$['\r']
+                )
+            ];$['\r']
+
+            $(if !chords.is_empty() {
+                $['\n']
+                $(format!("/// Combos, sent as the keys in row {matrix_rows}."))$['\r']
+                pub const CHORDS: [ChordDef; $(chords.len())] = [$['\r']
+                    $(for chord in chords => $chord,$['\r'])
+                ];$['\r']
+            })
+        };
+        tokens.to_file_string().unwrap()
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = transparent_outputs(keymap);
+        diagnostics.extend(check_matrix(&self.matrix, keymap));
+        for (location, key) in keymap.located_keys() {
+            let (KeyDef::Tap(tap) | KeyDef::TapHold(tap, _)) = key;
+            if !is_supported(tap) {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: {tap} isn't supported by keyberon, so it does nothing"
+                )));
+            }
+        }
+        if keymap.overrides().next().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "Key overrides aren't supported by keyberon, so they're left out",
+            ));
+        }
+        if keymap.encoder_count() > 0 {
+            diagnostics.push(Diagnostic::warning(
+                "Encoders aren't supported by keyberon, so they're left out",
+            ));
+        }
+        let layers = keymap.layers.len();
+        for (location, combo) in keymap.located_combos() {
+            if combo.layers.len() < layers {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: keyberon chords are active on every layer, so on other layers it does nothing"
+                )));
+            }
+        }
+        diagnostics
+    }
+}
+
+impl Keyberon {
+    fn kd(&self, keydef: &KeyDef) -> String {
+        match keydef {
+            KeyDef::Tap(tap) => tk(tap),
+            KeyDef::TapHold(tap, hold) => {
+                let hold = match hold {
+                    HoldKey::Layer(layer) => format!("l({layer})"),
+                    HoldKey::Modifier(modifier) => format!("k({})", mc(modifier)),
+                };
+                format!(
+                    "HoldTap(&HoldTapAction {{ timeout: {}, hold: {hold}, tap: {}, config: HoldTapConfig::Default, tap_hold_interval: 0 }})",
+                    self.tapping_term,
+                    tk(tap)
+                )
+            }
+        }
+    }
+}
+
+fn is_supported(tap: &TapKey) -> bool {
+    match tap {
+        TapKey::Key(key) | TapKey::Modified(_, key) => kc(key).is_some(),
+        TapKey::Layer(_) => true,
+        TapKey::ToggleLayer(_) | TapKey::OneShotMod(_) | TapKey::OneShotLayer(_) => false,
+    }
+}
+
+fn tk(tap: &TapKey) -> String {
+    match tap {
+        TapKey::Key(Key::Unassigned) => "Trans".to_string(),
+        TapKey::Key(Key::NotAllowed) => "NoOp".to_string(),
+        TapKey::Key(key) => match kc(key) {
+            Some(code) if typed_with(key).is_empty() => format!("k({code})"),
+            Some(code) => multiple(send_mods(&[], key, mc, "RAlt"), code),
+            None => "NoOp".to_string(),
+        },
+        TapKey::Layer(layer) => format!("l({layer})"),
+        TapKey::Modified(modifiers, key) => match kc(key) {
+            Some(code) => multiple(send_mods(modifiers, key, mc, "RAlt"), code),
+            None => "NoOp".to_string(),
+        },
+        // No equivalent action.
+        TapKey::ToggleLayer(_) | TapKey::OneShotMod(_) | TapKey::OneShotLayer(_) => {
+            "NoOp".to_string()
+        }
+    }
+}
+
+/// Keycodes pressed together, modifiers first.
+fn multiple(mods: Vec<&str>, code: &str) -> String {
+    format!("m(&&[{}, {code}][..])", mods.join(", "))
+}
+
+fn mc(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Alt => "LAlt",
+        Modifier::Meta => "LGui",
+        Modifier::Ctrl => "LCtrl",
+        Modifier::Shift => "LShift",
+    }
+}
+
+/// keyberon's `KeyCode` for a key, if it has one,
+/// sent with the modifiers it's [`typed_with`].
+fn kc(key: &Key) -> Option<&'static str> {
+    let code = match key {
+        Key::A => "A",
+        Key::B => "B",
+        Key::C => "C",
+        Key::D => "D",
+        Key::E => "E",
+        Key::F => "F",
+        Key::G => "G",
+        Key::H => "H",
+        Key::I => "I",
+        Key::J => "J",
+        Key::K => "K",
+        Key::L => "L",
+        Key::M => "M",
+        Key::N => "N",
+        Key::O => "O",
+        Key::P => "P",
+        Key::Q => "Q",
+        Key::R => "R",
+        Key::S => "S",
+        Key::T => "T",
+        Key::U => "U",
+        Key::V => "V",
+        Key::W => "W",
+        Key::X => "X",
+        Key::Y => "Y",
+        Key::Z => "Z",
+
+        Key::Num0 => "Kb0",
+        Key::Num1 => "Kb1",
+        Key::Num2 => "Kb2",
+        Key::Num3 => "Kb3",
+        Key::Num4 => "Kb4",
+        Key::Num5 => "Kb5",
+        Key::Num6 => "Kb6",
+        Key::Num7 => "Kb7",
+        Key::Num8 => "Kb8",
+        Key::Num9 => "Kb9",
+
+        Key::Pipe => "Bslash",
+        Key::Plus => "Equal",
+        Key::Minus => "Minus",
+        Key::Equal => "Equal",
+        Key::Comma => "Comma",
+        Key::Period => "Dot",
+        Key::Colon => "SColon",
+        Key::Caret => "Kb6",
+        Key::Dollar => "Kb4",
+        Key::Percent => "Kb5",
+        Key::Semicolon => "SColon",
+        Key::Question => "Slash",
+        Key::Exclamation => "Kb1",
+        Key::Octothorpe => "Kb3",
+        Key::Ampersand => "Kb7",
+        Key::Arobase => "Kb2",
+        Key::Asterisk => "Kb8",
+        Key::Slash => "Slash",
+        Key::Tilde => "Grave",
+        Key::Backtick => "Grave",
+        Key::LessThan => "Comma",
+        Key::GreaterThan => "Dot",
+        Key::ParensRight => "Kb0",
+        Key::ParensLeft => "Kb9",
+        Key::BracketRight => "RBracket",
+        Key::BracketLeft => "LBracket",
+        Key::BraceRight => "RBracket",
+        Key::BraceLeft => "LBracket",
+        Key::Underscore => "Minus",
+        Key::Apostrophe => "Quote",
+        Key::Backslash => "Bslash",
+        Key::Quote => "Quote",
+
+        Key::Up => "Up",
+        Key::Down => "Down",
+        Key::Left => "Left",
+        Key::Right => "Right",
+        Key::PageUp => "PgUp",
+        Key::PageDown => "PgDown",
+
+        Key::VolumeUp => "VolUp",
+        Key::VolumeDown => "VolDown",
+        Key::VolumeMute => "Mute",
+
+        Key::Aigu => "Quote",
+        Key::Grave => "Grave",
+        Key::Cedille => "Comma",
+        Key::Circonflex => "Kb6",
+        Key::Trema => "Quote",
+        Key::Euro => "Equal",
+
+        Key::Esc => "Escape",
+        Key::Tab => "Tab",
+        Key::Space => "Space",
+        Key::Backspace => "BSpace",
+        Key::Enter => "Enter",
+        Key::Meta => "LGui",
+        Key::Shift => "LShift",
+        Key::Ctrl => "LCtrl",
+        Key::Alt => "LAlt",
+
+        Key::NotAllowed => "No",
+        Key::Unassigned => "No",
+
+        // No appropriate keycode.
+        Key::BrightnessUp
+        | Key::BrightnessDown
+        | Key::MouseWheelUp
+        | Key::MouseWheelDown
+        | Key::CapsWord
+        | Key::Bt0
+        | Key::Bt1
+        | Key::BtClear
+        | Key::RgbToggle
+        | Key::RgbEffectNext
+        | Key::RgbEffectPrev
+        | Key::RgbHueUp
+        | Key::RgbHueDown
+        | Key::RgbSaturationUp
+        | Key::RgbSaturationDown
+        | Key::RgbBrightnessUp
+        | Key::RgbBrightnessDown
+        | Key::BacklightToggle
+        | Key::BacklightUp
+        | Key::BacklightDown
+        | Key::ExtPowerToggle
+        | Key::ExtPowerOn
+        | Key::ExtPowerOff
+        | Key::Bootloader => return None,
+    };
+    Some(code)
+}
@@ -0,0 +1,413 @@
+use std::fmt::Write;
+
+use super::{Format, send_mods, transparent_outputs};
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::KeyMap,
+    keys::{HoldKey, Key, KeyDef, Modifier, TapKey},
+};
+
+/// A KMonad `.kbd` config, for practicing the layout on a laptop keyboard.
+/// Keys that aren't in the layout fall through unchanged.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct KMonad {
+    /// The keyboard's input device.
+    pub device: String,
+
+    /// The KMonad name of the physical key for each key in the layout, in order.
+    /// If empty, each row of the layout is centered on a row of a laptop keyboard.
+    pub defsrc: Vec<String>,
+
+    /// The `tap-hold-next-release` timeout in ms.
+    pub tapping_term: u16,
+}
+impl Default for KMonad {
+    fn default() -> Self {
+        Self {
+            device: "/dev/input/by-path/platform-i8042-serio-0-event-kbd".to_string(),
+            defsrc: vec![],
+            tapping_term: 200,
+        }
+    }
+}
+impl KMonad {
+    /// Options from the `KMONAD_DEVICE`, `KMONAD_DEFSRC` (space-separated)
+    /// and `KMONAD_TAPPING_TERM` environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            device: std::env::var("KMONAD_DEVICE").unwrap_or(default.device),
+            defsrc: std::env::var("KMONAD_DEFSRC")
+                .map(|v| v.split_whitespace().map(str::to_string).collect())
+                .unwrap_or(default.defsrc),
+            tapping_term: std::env::var("KMONAD_TAPPING_TERM")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.tapping_term),
+        }
+    }
+}
+
+impl Format for KMonad {
+    fn format(&self, keymap: &KeyMap) -> String {
+        let Some(base) = keymap.layers.first() else {
+            return String::new();
+        };
+        let defsrc = self.defsrc(keymap).unwrap_or_default();
+
+        let mut kbd = String::new();
+        writeln!(kbd, "(defcfg").unwrap();
+        writeln!(kbd, "  input (device-file \"{}\")", self.device).unwrap();
+        writeln!(kbd, "  output (uinput-sink \"kbl\")").unwrap();
+        writeln!(kbd, "  fallthrough true").unwrap();
+        writeln!(kbd, ")").unwrap();
+
+        writeln!(kbd).unwrap();
+        writeln!(kbd, "(defsrc").unwrap();
+        for row in rows(&base.rows, &defsrc) {
+            writeln!(kbd, "  {}", row.join(" ")).unwrap();
+        }
+        writeln!(kbd, ")").unwrap();
+
+        for layer in &keymap.layers {
+            let keys: Vec<_> = layer.layout.iter().map(|key| self.kd(key)).collect();
+            writeln!(kbd).unwrap();
+            writeln!(kbd, "(deflayer {}", layer.name).unwrap();
+            for row in rows(&layer.rows, &keys) {
+                writeln!(kbd, "  {}", row.join(" ")).unwrap();
+            }
+            writeln!(kbd, ")").unwrap();
+        }
+        kbd.trim_end().to_string()
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = transparent_outputs(keymap);
+        match self.defsrc(keymap) {
+            Ok(defsrc) => {
+                // KMonad needs every deflayer to have as many keys as defsrc.
+                let count = |rows: Vec<&[String]>| rows.iter().map(|row| row.len()).sum::<usize>();
+                let sources = count(rows(
+                    keymap
+                        .layers
+                        .first()
+                        .map_or(&[], |base| base.rows.as_slice()),
+                    &defsrc,
+                ));
+                for layer in &keymap.layers {
+                    let keys: Vec<_> = layer.layout.iter().map(|key| self.kd(key)).collect();
+                    let written = count(rows(&layer.rows, &keys));
+                    if written != sources || written != keys.len() {
+                        diagnostics.push(Diagnostic::error(format!(
+                            "Layer {}: The deflayer has {written} of the layer's {} keys but defsrc has {sources}",
+                            layer.name,
+                            keys.len()
+                        )));
+                    }
+                }
+            }
+            Err(err) => diagnostics.push(Diagnostic::error(err)),
+        }
+        for (location, key) in keymap.located_keys() {
+            let (KeyDef::Tap(tap) | KeyDef::TapHold(tap, _)) = key;
+            if !is_supported(tap) {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: {tap} isn't supported by KMonad, so it does nothing"
+                )));
+            }
+        }
+        for (features, present) in [
+            ("Combos", keymap.combos().next().is_some()),
+            ("Key overrides", keymap.overrides().next().is_some()),
+            ("Encoders", keymap.encoder_count() > 0),
+        ] {
+            if present {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{features} aren't supported by KMonad, so they're left out"
+                )));
+            }
+        }
+        diagnostics
+    }
+}
+
+impl KMonad {
+    /// The physical key for each key in the layout.
+    fn defsrc(&self, keymap: &KeyMap) -> Result<Vec<String>, String> {
+        let Some(base) = keymap.layers.first() else {
+            return Ok(vec![]);
+        };
+        if !self.defsrc.is_empty() {
+            return if self.defsrc.len() == base.layout.len() {
+                Ok(self.defsrc.clone())
+            } else {
+                Err(format!(
+                    "defsrc has {} keys but the layout has {}",
+                    self.defsrc.len(),
+                    base.layout.len()
+                ))
+            };
+        }
+
+        // Layouts with a number row start from it.
+        let laptop = match base.rows.len() {
+            0..=4 => &LAPTOP[1..],
+            5 => LAPTOP,
+            rows => {
+                return Err(format!(
+                    "The layout has {rows} rows, too many for a laptop keyboard. Set defsrc instead"
+                ));
+            }
+        };
+        let mut defsrc = vec![];
+        for (i, (len, (keys, center))) in base.rows.iter().zip(laptop).enumerate() {
+            let start = center
+                .checked_sub(len / 2)
+                .filter(|start| start + len <= keys.len());
+            let Some(start) = start else {
+                return Err(format!(
+                    "Row {i} has {len} keys, too many to center on a laptop keyboard. Set defsrc instead"
+                ));
+            };
+            defsrc.extend(keys[start..start + len].iter().map(|key| key.to_string()));
+        }
+        Ok(defsrc)
+    }
+
+    fn kd(&self, keydef: &KeyDef) -> String {
+        match keydef {
+            KeyDef::Tap(tap) => tk(tap),
+            KeyDef::TapHold(tap, hold) => {
+                let hold = match hold {
+                    HoldKey::Layer(layer) => format!("(layer-toggle {layer})"),
+                    HoldKey::Modifier(modifier) => mc(modifier).to_string(),
+                };
+                format!(
+                    "(tap-hold-next-release {} {} {hold})",
+                    self.tapping_term,
+                    tk(tap)
+                )
+            }
+        }
+    }
+}
+
+/// The keys of a laptop keyboard's rows, with the index each row
+/// is centered on, i.e. where the right hand's keys start.
+const LAPTOP: &[(&[&str], usize)] = &[
+    (
+        &[
+            "grv", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "-", "=",
+        ],
+        6,
+    ),
+    (
+        &[
+            "tab", "q", "w", "e", "r", "t", "y", "u", "i", "o", "p", "[", "]", "\\\\",
+        ],
+        6,
+    ),
+    (
+        &[
+            "caps", "a", "s", "d", "f", "g", "h", "j", "k", "l", ";", "'", "ret",
+        ],
+        6,
+    ),
+    (
+        &[
+            "lsft", "z", "x", "c", "v", "b", "n", "m", ",", ".", "/", "rsft",
+        ],
+        6,
+    ),
+    (&["lctl", "lmet", "lalt", "spc", "ralt", "rmet", "rctl"], 4),
+];
+
+/// Keys split into the layout's rows.
+fn rows<'a>(rows: &[usize], keys: &'a [String]) -> Vec<&'a [String]> {
+    let mut start = 0;
+    rows.iter()
+        .filter_map(|len| {
+            let row = keys.get(start..start + len);
+            start += len;
+            row
+        })
+        .collect()
+}
+
+fn is_supported(tap: &TapKey) -> bool {
+    match tap {
+        TapKey::Key(key) | TapKey::Modified(_, key) => kc(key).is_some(),
+        TapKey::Layer(_) | TapKey::OneShotMod(_) | TapKey::OneShotLayer(_) => true,
+        TapKey::ToggleLayer(_) => false,
+    }
+}
+
+fn tk(tap: &TapKey) -> String {
+    match tap {
+        TapKey::Key(key) => match kc(key) {
+            Some(code) => around(send_mods(&[], key, mc, "ralt"), code),
+            None => "XX".to_string(),
+        },
+        TapKey::Layer(layer) => format!("(layer-toggle {layer})"),
+        TapKey::OneShotMod(modifier) => format!("(sticky-key 1000 {})", mc(modifier)),
+        TapKey::OneShotLayer(layer) => format!("(sticky-key 1000 (layer-toggle {layer}))"),
+        TapKey::Modified(modifiers, key) => match kc(key) {
+            Some(code) => around(send_mods(modifiers, key, mc, "ralt"), code),
+            None => "XX".to_string(),
+        },
+        // KMonad's `layer-switch` changes the base layer, rather than toggling.
+        TapKey::ToggleLayer(_) => "XX".to_string(),
+    }
+}
+
+/// A key pressed while holding modifiers, `(around lsft (around lctl z))`.
+fn around(mods: Vec<&str>, code: &str) -> String {
+    mods.into_iter()
+        .rev()
+        .fold(code.to_string(), |button, modifier| {
+            format!("(around {modifier} {button})")
+        })
+}
+
+fn mc(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Alt => "lalt",
+        Modifier::Meta => "lmet",
+        Modifier::Ctrl => "lctl",
+        Modifier::Shift => "lsft",
+    }
+}
+
+/// KMonad's name for a key, if it has one, sent with the modifiers it's [`typed_with`](super::typed_with).
+fn kc(key: &Key) -> Option<&'static str> {
+    let code = match key {
+        Key::A => "a",
+        Key::B => "b",
+        Key::C => "c",
+        Key::D => "d",
+        Key::E => "e",
+        Key::F => "f",
+        Key::G => "g",
+        Key::H => "h",
+        Key::I => "i",
+        Key::J => "j",
+        Key::K => "k",
+        Key::L => "l",
+        Key::M => "m",
+        Key::N => "n",
+        Key::O => "o",
+        Key::P => "p",
+        Key::Q => "q",
+        Key::R => "r",
+        Key::S => "s",
+        Key::T => "t",
+        Key::U => "u",
+        Key::V => "v",
+        Key::W => "w",
+        Key::X => "x",
+        Key::Y => "y",
+        Key::Z => "z",
+
+        Key::Num0 => "0",
+        Key::Num1 => "1",
+        Key::Num2 => "2",
+        Key::Num3 => "3",
+        Key::Num4 => "4",
+        Key::Num5 => "5",
+        Key::Num6 => "6",
+        Key::Num7 => "7",
+        Key::Num8 => "8",
+        Key::Num9 => "9",
+
+        Key::Pipe => "\\\\",
+        Key::Plus => "=",
+        Key::Minus => "-",
+        Key::Equal => "=",
+        Key::Comma => ",",
+        Key::Period => ".",
+        Key::Colon => ";",
+        Key::Caret => "6",
+        Key::Dollar => "4",
+        Key::Percent => "5",
+        Key::Semicolon => ";",
+        Key::Question => "/",
+        Key::Exclamation => "1",
+        Key::Octothorpe => "3",
+        Key::Ampersand => "7",
+        Key::Arobase => "2",
+        Key::Asterisk => "8",
+        Key::Slash => "/",
+        Key::Tilde => "grv",
+        Key::Backtick => "grv",
+        Key::LessThan => ",",
+        Key::GreaterThan => ".",
+        Key::ParensRight => "0",
+        Key::ParensLeft => "9",
+        Key::BracketRight => "]",
+        Key::BracketLeft => "[",
+        Key::BraceRight => "]",
+        Key::BraceLeft => "[",
+        Key::Underscore => "-",
+        Key::Apostrophe => "'",
+        Key::Backslash => "\\\\",
+        Key::Quote => "'",
+
+        Key::Up => "up",
+        Key::Down => "down",
+        Key::Left => "left",
+        Key::Right => "right",
+        Key::PageUp => "pgup",
+        Key::PageDown => "pgdn",
+
+        Key::VolumeUp => "volu",
+        Key::VolumeDown => "vold",
+        Key::VolumeMute => "mute",
+        Key::BrightnessUp => "brup",
+        Key::BrightnessDown => "brdn",
+
+        Key::Aigu => "'",
+        Key::Grave => "grv",
+        Key::Cedille => ",",
+        Key::Circonflex => "6",
+        Key::Trema => "'",
+        Key::Euro => "=",
+
+        Key::Esc => "esc",
+        Key::Tab => "tab",
+        Key::Space => "spc",
+        Key::Backspace => "bspc",
+        Key::Enter => "ret",
+        Key::Meta => "lmet",
+        Key::Shift => "lsft",
+        Key::Ctrl => "lctl",
+        Key::Alt => "lalt",
+
+        Key::NotAllowed => "XX",
+        Key::Unassigned => "_",
+
+        // No appropriate key.
+        Key::MouseWheelUp
+        | Key::MouseWheelDown
+        | Key::CapsWord
+        | Key::Bt0
+        | Key::Bt1
+        | Key::BtClear
+        | Key::RgbToggle
+        | Key::RgbEffectNext
+        | Key::RgbEffectPrev
+        | Key::RgbHueUp
+        | Key::RgbHueDown
+        | Key::RgbSaturationUp
+        | Key::RgbSaturationDown
+        | Key::RgbBrightnessUp
+        | Key::RgbBrightnessDown
+        | Key::BacklightToggle
+        | Key::BacklightUp
+        | Key::BacklightDown
+        | Key::ExtPowerToggle
+        | Key::ExtPowerOn
+        | Key::ExtPowerOff
+        | Key::Bootloader => return None,
+    };
+    Some(code)
+}
@@ -1,10 +1,171 @@
+mod json;
+mod keyberon;
+mod kmonad;
 mod qmk;
+mod qmk_json;
+mod rmk;
+mod vial;
 mod zmk;
 
-use crate::keymap::KeyMap;
+use std::path::PathBuf;
+
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::{KeyMap, Location},
+    keys::{Key, KeyDef, Modifier, TapKey},
+};
+pub use json::JSON;
+pub use keyberon::Keyberon;
+pub use kmonad::KMonad;
 pub use qmk::QMK;
+pub use qmk_json::QMKJSON;
+pub use rmk::RMK;
+pub use vial::Vial;
 pub use zmk::ZMK;
 
+/// A target the keymap can be generated for.
+/// The target's options are set on the implementing type.
 pub trait Format {
-    fn format(keymap: &KeyMap) -> String;
+    /// The keymap file for the target.
+    fn format(&self, keymap: &KeyMap) -> String;
+
+    /// A complete config directory for the target, as
+    /// `(relative path, contents)` pairs, if the target supports it.
+    fn tree(&self, _keymap: &KeyMap) -> Option<Vec<(PathBuf, String)>> {
+        None
+    }
+
+    /// Problems with keys that won't work as intended on the target.
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        transparent_outputs(keymap)
+    }
+
+    /// Problems when the keymap is written as a [`tree`](Format::tree),
+    /// whose config can take care of some of those found by `check`.
+    fn check_tree(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        self.check(keymap)
+    }
+}
+
+/// Unassigned keys are transparent, which only makes sense in a layout.
+/// As the output of a combo or key override they do nothing.
+pub fn transparent_outputs(keymap: &KeyMap) -> Vec<Diagnostic> {
+    keymap
+        .located_keys()
+        .filter(|(location, key)| {
+            matches!(location, Location::Combo { .. } | Location::Override { .. })
+                && **key == KeyDef::Tap(TapKey::Key(Key::Unassigned))
+        })
+        .map(|(location, _)| {
+            Diagnostic::warning(format!(
+                "{location}: Unassigned keys are transparent, so this does nothing"
+            ))
+        })
+        .collect()
+}
+
+/// The matrix `(row, column)` of each key in the layout, for targets that
+/// place keys by the keyboard's matrix. Without a `matrix`, the keys'
+/// rows and columns in the layout are used.
+fn matrix_positions(matrix: &[(usize, usize)], keymap: &KeyMap) -> Vec<(usize, usize)> {
+    if matrix.is_empty() {
+        let Some(base) = keymap.layers.first() else {
+            return vec![];
+        };
+        (0..base.layout.len())
+            .map(|position| base.coordinates(position))
+            .collect()
+    } else {
+        matrix.to_vec()
+    }
+}
+
+/// The matrix needs a position for every key in the layout.
+fn check_matrix(matrix: &[(usize, usize)], keymap: &KeyMap) -> Option<Diagnostic> {
+    let keys = keymap.layers.first().map_or(0, |base| base.layout.len());
+    (!matrix.is_empty() && matrix.len() != keys).then(|| {
+        Diagnostic::error(format!(
+            "The matrix has {} positions but the layout has {keys} keys",
+            matrix.len()
+        ))
+    })
+}
+
+/// The index of a layer, for targets that refer to layers by index.
+fn layer_index(keymap: &KeyMap, name: &str) -> usize {
+    keymap
+        .layers
+        .iter()
+        .position(|layer| layer.name == name)
+        .unwrap_or_default()
+}
+
+/// A matrix given as space-separated `row,column` pairs.
+fn parse_matrix(s: &str) -> Vec<(usize, usize)> {
+    s.split_whitespace()
+        .map(|pair| {
+            let (row, col) = pair.split_once(',').unwrap();
+            (row.parse().unwrap(), col.parse().unwrap())
+        })
+        .collect()
+}
+
+/// A modifier a symbol is typed with on a US International layout,
+/// for targets whose keycodes are only the physical keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Typed {
+    Shift,
+    AltGr,
+}
+
+/// The modifiers a key's symbol is typed with, e.g. Shift for `(`,
+/// on top of the physical key the target's keycode names.
+fn typed_with(key: &Key) -> &'static [Typed] {
+    match key {
+        Key::Pipe
+        | Key::Plus
+        | Key::Colon
+        | Key::Caret
+        | Key::Dollar
+        | Key::Percent
+        | Key::Question
+        | Key::Exclamation
+        | Key::Octothorpe
+        | Key::Ampersand
+        | Key::Arobase
+        | Key::Asterisk
+        | Key::Tilde
+        | Key::LessThan
+        | Key::GreaterThan
+        | Key::ParensRight
+        | Key::ParensLeft
+        | Key::BraceRight
+        | Key::BraceLeft
+        | Key::Underscore
+        | Key::Quote => &[Typed::Shift],
+        Key::Aigu | Key::Grave | Key::Cedille | Key::Euro => &[Typed::AltGr],
+        Key::Circonflex | Key::Trema => &[Typed::Shift, Typed::AltGr],
+        _ => &[],
+    }
+}
+
+/// The modifiers to send a key with, in the target's names: the ones it's
+/// modified with, then those its symbol is typed with that aren't already.
+fn send_mods<'a>(
+    modifiers: &[Modifier],
+    key: &Key,
+    mc: fn(&Modifier) -> &'a str,
+    altgr: &'a str,
+) -> Vec<&'a str> {
+    let mut mods: Vec<_> = modifiers.iter().map(mc).collect();
+    for typed in typed_with(key) {
+        let typed = match typed {
+            Typed::Shift => mc(&Modifier::Shift),
+            Typed::AltGr => altgr,
+        };
+        if !mods.contains(&typed) {
+            mods.push(typed);
+        }
+    }
+    mods
 }
@@ -1,22 +1,75 @@
-use super::Format;
+use std::path::PathBuf;
+
+use super::{Format, transparent_outputs};
 use crate::{
-    keymap::{Combo, KeyMap, Layer, LayerKey, Shifted},
+    diagnostic::Diagnostic,
+    keymap::{Combo, Encoder, Features, KeyMap, KeyOverride, Layer, Location, Section},
     keys::{HoldKey, Key, KeyDef, Modifier, TapKey},
 };
 
 use genco::prelude::*;
 
+/// A QMK `keymap.c`, or with `tree` a keymap directory.
 #[allow(clippy::upper_case_acronyms)]
-pub struct QMK;
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct QMK {
+    /// The keyboard's layout macro, e.g. `LAYOUT_split_3x6_3`.
+    pub layout_macro: String,
+
+    /// The `TAPPING_TERM` in ms.
+    pub tapping_term: u16,
+
+    /// The `COMBO_TERM` in ms.
+    pub combo_term: u16,
+
+    /// QMK has separate keycodes for RGB underglow (`UG_*`)
+    /// and RGB matrix (`RM_*`) lighting. Underglow is the default.
+    pub rgb_matrix: bool,
+}
+impl Default for QMK {
+    fn default() -> Self {
+        Self {
+            layout_macro: "LAYOUT_split_3x5_2".to_string(),
+            tapping_term: 200,
+            combo_term: 45,
+            rgb_matrix: false,
+        }
+    }
+}
+impl QMK {
+    /// Options from the `QMK_LAYOUT_MACRO`, `QMK_TAPPING_TERM`,
+    /// `QMK_COMBO_TERM` and `QMK_RGB_MATRIX` environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            layout_macro: std::env::var("QMK_LAYOUT_MACRO").unwrap_or(default.layout_macro),
+            tapping_term: std::env::var("QMK_TAPPING_TERM")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.tapping_term),
+            combo_term: std::env::var("QMK_COMBO_TERM")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.combo_term),
+            rgb_matrix: std::env::var_os("QMK_RGB_MATRIX").is_some(),
+        }
+    }
+}
 
 impl Format for QMK {
-    fn format(keymap: &KeyMap) -> String {
-        let include = &keymap.include;
+    fn format(&self, keymap: &KeyMap) -> String {
+        // QMK has no equivalent to the `behaviors` and `root` sections.
+        let header = keymap.include("qmk", Section::Header, Section::Header);
+        let footer = keymap.include("qmk", Section::Footer, Section::Header);
+        let encoders = keymap.encoder_count();
+        let Some(base) = keymap.layers.first() else {
+            return String::new();
+        };
+        let layout_macro = &self.layout_macro;
         let tokens: Tokens<C> = quote! {
             #include QMK_KEYBOARD_H
-            #define LAYOUT LAYOUT_split_3x5_2
+            #define LAYOUT $layout_macro
 
-            $include
+            $header
 
             enum layers {
                 $(for Layer { name, .. } in &keymap.layers join(,) => $name)
@@ -26,7 +79,7 @@ impl Format for QMK {
                 $(for Layer { name, layout, .. } in &keymap.layers join(,$['\r']) =>
                     [$name] = LAYOUT(
                         $(for key in layout join(, ) =>
-                            $(kd(key))
+                            $(self.kd(key))
                         )
                     )
                 )
@@ -34,145 +87,494 @@ impl Format for QMK {
 
             // Combos
             $(for (i, combo) in keymap.combos().enumerate() join($['\r']) {
-                const uint16_t PROGMEM combo_$i[] = {$(for LayerKey { key, .. } in &combo.inputs join(, ) => $(kd(key))), COMBO_END};
+                const uint16_t PROGMEM combo_$i[] = {$(for position in &combo.inputs join(, ) => $(base.layout.get(*position).map_or_else(|| "KC_NO".to_string(), |key| self.kd(key)))), COMBO_END};
             })
             combo_t key_combos[] = {
                 $(for (i, Combo { output, .. }) in keymap.combos().enumerate() join(,$['\r']) {
-                    COMBO(combo_$i, $(kd(output)))
+                    COMBO(combo_$i, $(self.kd(output)))
                 })
             };
+            $(if keymap.combos().next().is_some() {
+                // Combo keys are always looked up on the base layer,
+                // so that combos are based on key positions.
+                uint8_t combo_ref_from_layer(uint8_t layer) {
+                    return $(&base.name);
+                }
+
+                // Only trigger combos on their layers.
+                // Requires `#define COMBO_SHOULD_TRIGGER` in `config.h`.
+                bool combo_should_trigger(uint16_t combo_index, combo_t *combo, uint16_t keycode, keyrecord_t *record) {
+                    uint8_t layer = get_highest_layer(layer_state | default_layer_state);
+                    switch (combo_index) {
+                        $(for (i, Combo { layers, .. }) in keymap.combos().enumerate() join($['\r']) {
+                            case $i: return $(for name in layers join( || ) => layer == $name);
+                        })
+                    }
+                    return true;
+                }
+            })
 
-            // Shift overrides
-            $(for Shifted { name, input, output } in keymap.shifts() join($['\r']) {
-                const key_override_t $name = ko_make_basic(MOD_MASK_SHIFT, $(kd(input)), $(kd(output)));
+            // Key overrides
+            $(for key_override in overrides(keymap, false) join($['\r']) {
+                const key_override_t $(&key_override.name) = $(self.ko(key_override));
             })
             const key_override_t *key_overrides[] = {
-                $(for Shifted { name, .. } in keymap.shifts() join(,$['\r']) => &$name)
+                $(for KeyOverride { name, .. } in overrides(keymap, false) join(,$['\r']) => &$name)
             };
+            $(if overrides(keymap, true).next().is_some() {
+                // Key overrides on hold-tap keys are applied to the tap only,
+                // since `key_overrides` doesn't handle them reliably.
+                bool process_record_user(uint16_t keycode, keyrecord_t *record) {
+                    uint8_t mods = get_mods();
+                    uint8_t layer = get_highest_layer(layer_state | default_layer_state);
+                    $(for key_override in overrides(keymap, true) join($['\r']) {
+                        if ($(self.tap_override(key_override))) {
+                            del_mods($(mod_mask(&suppressed_mods(key_override))));
+                            tap_code16($(self.kd(&key_override.output)));
+                            set_mods(mods);
+                            return false;
+                        }
+                    })
+                    return true;
+                }
+            })
+
+            $(if encoders > 0 {
+                // Encoders
+                #if defined(ENCODER_MAP_ENABLE)
+                const uint16_t PROGMEM encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {
+                    $(for Layer { name, encoders: bindings, .. } in &keymap.layers join(,$['\r']) =>
+                        [$name] = { $(for i in 0..encoders join(, ) => $(self.encoder(bindings.get(&i)))) }
+                    )
+                };
+                #endif
+            })
+
+            $footer
         };
         tokens.to_file_string().unwrap()
     }
+
+    fn tree(&self, keymap: &KeyMap) -> Option<Vec<(PathBuf, String)>> {
+        let features = keymap.features();
+        Some(vec![
+            ("keymap.c".into(), self.format(keymap)),
+            ("rules.mk".into(), self.rules(&features)),
+            ("config.h".into(), self.config(&features)),
+        ])
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = self.check_keys(keymap);
+        for (location, key) in keymap.located_keys() {
+            if let Location::Override { .. } = location
+                && !self.is_modified_basic(key)
+            {
+                diagnostics.push(Diagnostic::error(format!(
+                    "{location}: QMK overrides can only send a key with modifiers, not {key:?}"
+                )));
+            }
+        }
+        let defines_process_record = keymap
+            .includes
+            .iter()
+            .filter(|include| include.target.as_deref().is_none_or(|t| t == "qmk"))
+            .any(|include| include.code.contains("process_record_user"));
+        if defines_process_record && overrides(keymap, true).next().is_some() {
+            diagnostics.push(Diagnostic::error(
+                "Overrides on hold-tap keys are handled in a generated process_record_user, so it can't also be defined in included code",
+            ));
+        }
+        for include in &keymap.includes {
+            let section = match include.section {
+                Some(Section::Behaviors) => "behaviors",
+                Some(Section::Root) => "root",
+                _ => continue,
+            };
+            if include.target.as_deref().is_none_or(|t| t == "qmk") {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "keymap.c has no {section} section, so code included there is left out. Target it at zmk"
+                )));
+            }
+        }
+        diagnostics
+    }
 }
 
-fn kc(key: &Key) -> &'static str {
-    match key {
-        Key::A => "KC_A",
-        Key::B => "KC_B",
-        Key::C => "KC_C",
-        Key::D => "KC_D",
-        Key::E => "KC_E",
-        Key::F => "KC_F",
-        Key::G => "KC_G",
-        Key::H => "KC_H",
-        Key::I => "KC_I",
-        Key::J => "KC_J",
-        Key::K => "KC_K",
-        Key::L => "KC_L",
-        Key::M => "KC_M",
-        Key::N => "KC_N",
-        Key::O => "KC_O",
-        Key::P => "KC_P",
-        Key::Q => "KC_Q",
-        Key::R => "KC_R",
-        Key::S => "KC_S",
-        Key::T => "KC_T",
-        Key::U => "KC_U",
-        Key::V => "KC_V",
-        Key::W => "KC_W",
-        Key::X => "KC_X",
-        Key::Y => "KC_Y",
-        Key::Z => "KC_Z",
-
-        Key::Num0 => "KC_0",
-        Key::Num1 => "KC_1",
-        Key::Num2 => "KC_2",
-        Key::Num3 => "KC_3",
-        Key::Num4 => "KC_4",
-        Key::Num5 => "KC_5",
-        Key::Num6 => "KC_6",
-        Key::Num7 => "KC_7",
-        Key::Num8 => "KC_8",
-        Key::Num9 => "KC_9",
-
-        Key::Pipe => "LSFT(KC_BSLS)",
-        Key::Plus => "LSFT(KC_EQL)",
-        Key::Minus => "KC_MINUS",
-        Key::Equal => "KC_EQL",
-        Key::Comma => "KC_COMMA",
-        Key::Period => "KC_DOT",
-        Key::Colon => "LSFT(KC_SCLN)",
-        Key::Caret => "LSFT(KC_6)",
-        Key::Dollar => "LSFT(KC_4)",
-        Key::Percent => "LSFT(KC_5)",
-        Key::Semicolon => "KC_SCLN",
-        Key::Question => "LSFT(KC_SLSH)",
-        Key::Exclamation => "LSFT(KC_1)",
-        Key::Octothorpe => "LSFT(KC_3)",
-        Key::Ampersand => "LSFT(KC_7)",
-        Key::Arobase => "LSFT(KC_2)",
-        Key::Asterisk => "LSFT(KC_8)",
-        Key::Slash => "KC_SLSH",
-        Key::Tilde => "LSFT(KC_GRV)",
-        Key::Backtick => "KC_GRAVE",
-        Key::LessThan => "LSFT(KC_COMMA)",
-        Key::GreaterThan => "LSFT(KC_DOT)",
-        Key::ParensRight => "LSFT(KC_0)",
-        Key::ParensLeft => "LSFT(KC_9)",
-        Key::BracketRight => "KC_RBRC",
-        Key::BracketLeft => "KC_LBRC",
-        Key::BraceRight => "LSFT(KC_RBRC)",
-        Key::BraceLeft => "LSFT(KC_LBRC)",
-        Key::Underscore => "LSFT(KC_MINUS)",
-        Key::Apostrophe => "KC_QUOTE",
-        Key::Backslash => "KC_BACKSLASH",
-        Key::Quote => "LSFT(KC_QUOTE)",
-
-        Key::Up => "KC_UP",
-        Key::Down => "KC_DOWN",
-        Key::Left => "KC_LEFT",
-        Key::Right => "KC_RIGHT",
-        Key::PageUp => "KC_PGUP",
-        Key::PageDown => "KC_PGDN",
-
-        Key::VolumeUp => "KC_VOLU",
-        Key::VolumeDown => "KC_VOLD",
-        Key::VolumeMute => "KC_MUTE",
-        Key::BrightnessUp => "KC_BRIGHTNESS_UP",
-        Key::BrightnessDown => "KC_BRIGHTNESS_DOWN",
-
-        Key::Aigu => "RALT(KC_QUOTE)",
-        Key::Grave => "RALT(KC_GRAVE)",
-        Key::Cedille => "RALT(KC_COMMA)",
-        Key::Circonflex => "RALT(LSFT(KC_6))",
-        Key::Trema => "RALT(LSFT(KC_QUOTE))",
-        Key::Euro => "RALT(KC_EQUAL)",
-
-        Key::Esc => "KC_ESC",
-        Key::Tab => "KC_TAB",
-        Key::Space => "KC_SPACE",
-        Key::Backspace => "KC_BSPC",
-        Key::Enter => "KC_ENTER",
-        Key::Meta => "KC_LGUI",
-        Key::Shift => "KC_LSFT",
-        Key::Ctrl => "KC_LCTL",
-        Key::Alt => "KC_LALT",
-
-        Key::MouseWheelUp => "QK_MOUSE_WHEEL_UP",
-        Key::MouseWheelDown => "QK_MOUSE_WHEEL_DOWN",
-
-        Key::CapsWord => "QK_CAPS_WORD_TOGGLE",
-
-        Key::Bootloader => "QK_BOOT",
-
-        Key::NotAllowed => "KC_NO",
-        Key::Unassigned => "KC_TRNS",
-
-        // No appropriate keycode.
-        Key::Bt0 | Key::Bt1 | Key::BtClear => "KC_NO",
+impl QMK {
+    /// Problems with QMK keycodes, shared with the targets using them.
+    pub(super) fn check_keys(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut warnings = transparent_outputs(keymap);
+        for (location, key) in keymap.located_keys() {
+            let (KeyDef::Tap(tap) | KeyDef::TapHold(tap, _)) = key;
+            if let Some(
+                key @ (Key::Bt0
+                | Key::Bt1
+                | Key::BtClear
+                | Key::ExtPowerToggle
+                | Key::ExtPowerOn
+                | Key::ExtPowerOff),
+            ) = tap.key()
+            {
+                warnings.push(Diagnostic::warning(format!(
+                    "{location}: {key:?} isn't supported by QMK, so it's sent as KC_NO"
+                )));
+            }
+            if let KeyDef::TapHold(tap, _) = key
+                && !self.is_basic(tap)
+            {
+                warnings.push(Diagnostic::warning(format!(
+                    "{location}: MT and LT only tap basic keycodes in QMK, so {tap} loses its modifiers or does nothing"
+                )));
+            }
+            // Combos are matched by the keycodes on the base layer.
+            if let Location::Combo { inputs, .. } = location
+                && let Some(base) = keymap.layers.first()
+            {
+                let keycodes: Vec<_> = base.layout.iter().map(|key| self.kd(key)).collect();
+                for position in inputs {
+                    let Some(keycode) = keycodes.get(*position) else {
+                        continue;
+                    };
+                    if keycode == "KC_TRNS" || keycode == "KC_NO" {
+                        warnings.push(Diagnostic::warning(format!(
+                            "{location}: The base layer has {keycode} at position {position}, so QMK can't match the combo reliably"
+                        )));
+                    } else if keycodes.iter().filter(|other| *other == keycode).count() > 1 {
+                        warnings.push(Diagnostic::warning(format!(
+                            "{location}: {keycode} is on the base layer more than once, so the combo also fires from its other positions"
+                        )));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Build options enabling the features the keymap uses.
+    fn rules(&self, features: &Features) -> String {
+        let rgb = if self.rgb_matrix {
+            "RGB_MATRIX_ENABLE"
+        } else {
+            "RGBLIGHT_ENABLE"
+        };
+        [
+            (features.combos, "COMBO_ENABLE"),
+            (features.key_overrides, "KEY_OVERRIDE_ENABLE"),
+            (features.caps_word, "CAPS_WORD_ENABLE"),
+            (features.mouse_keys, "MOUSEKEY_ENABLE"),
+            (features.encoders, "ENCODER_ENABLE"),
+            (features.encoders, "ENCODER_MAP_ENABLE"),
+            (features.rgb, rgb),
+            (features.backlight, "BACKLIGHT_ENABLE"),
+        ]
+        .into_iter()
+        .filter(|(used, _)| *used)
+        .map(|(_, option)| format!("{option} = yes\n"))
+        .collect()
+    }
+
+    /// Timing config for the features the keymap uses.
+    fn config(&self, features: &Features) -> String {
+        let mut config = String::from("#pragma once\n");
+        if features.tap_hold {
+            let tapping_term = self.tapping_term;
+            config.push_str(&format!("\n#define TAPPING_TERM {tapping_term}\n"));
+        }
+        if features.combos {
+            let combo_term = self.combo_term;
+            config.push_str(&format!("\n#define COMBO_TERM {combo_term}\n"));
+            config.push_str("#define COMBO_SHOULD_TRIGGER\n");
+        }
+        config
+    }
+
+    fn kc(&self, key: &Key) -> &'static str {
+        match key {
+            Key::A => "KC_A",
+            Key::B => "KC_B",
+            Key::C => "KC_C",
+            Key::D => "KC_D",
+            Key::E => "KC_E",
+            Key::F => "KC_F",
+            Key::G => "KC_G",
+            Key::H => "KC_H",
+            Key::I => "KC_I",
+            Key::J => "KC_J",
+            Key::K => "KC_K",
+            Key::L => "KC_L",
+            Key::M => "KC_M",
+            Key::N => "KC_N",
+            Key::O => "KC_O",
+            Key::P => "KC_P",
+            Key::Q => "KC_Q",
+            Key::R => "KC_R",
+            Key::S => "KC_S",
+            Key::T => "KC_T",
+            Key::U => "KC_U",
+            Key::V => "KC_V",
+            Key::W => "KC_W",
+            Key::X => "KC_X",
+            Key::Y => "KC_Y",
+            Key::Z => "KC_Z",
+
+            Key::Num0 => "KC_0",
+            Key::Num1 => "KC_1",
+            Key::Num2 => "KC_2",
+            Key::Num3 => "KC_3",
+            Key::Num4 => "KC_4",
+            Key::Num5 => "KC_5",
+            Key::Num6 => "KC_6",
+            Key::Num7 => "KC_7",
+            Key::Num8 => "KC_8",
+            Key::Num9 => "KC_9",
+
+            Key::Pipe => "LSFT(KC_BSLS)",
+            Key::Plus => "LSFT(KC_EQL)",
+            Key::Minus => "KC_MINUS",
+            Key::Equal => "KC_EQL",
+            Key::Comma => "KC_COMMA",
+            Key::Period => "KC_DOT",
+            Key::Colon => "LSFT(KC_SCLN)",
+            Key::Caret => "LSFT(KC_6)",
+            Key::Dollar => "LSFT(KC_4)",
+            Key::Percent => "LSFT(KC_5)",
+            Key::Semicolon => "KC_SCLN",
+            Key::Question => "LSFT(KC_SLSH)",
+            Key::Exclamation => "LSFT(KC_1)",
+            Key::Octothorpe => "LSFT(KC_3)",
+            Key::Ampersand => "LSFT(KC_7)",
+            Key::Arobase => "LSFT(KC_2)",
+            Key::Asterisk => "LSFT(KC_8)",
+            Key::Slash => "KC_SLSH",
+            Key::Tilde => "LSFT(KC_GRV)",
+            Key::Backtick => "KC_GRAVE",
+            Key::LessThan => "LSFT(KC_COMMA)",
+            Key::GreaterThan => "LSFT(KC_DOT)",
+            Key::ParensRight => "LSFT(KC_0)",
+            Key::ParensLeft => "LSFT(KC_9)",
+            Key::BracketRight => "KC_RBRC",
+            Key::BracketLeft => "KC_LBRC",
+            Key::BraceRight => "LSFT(KC_RBRC)",
+            Key::BraceLeft => "LSFT(KC_LBRC)",
+            Key::Underscore => "LSFT(KC_MINUS)",
+            Key::Apostrophe => "KC_QUOTE",
+            Key::Backslash => "KC_BACKSLASH",
+            Key::Quote => "LSFT(KC_QUOTE)",
+
+            Key::Up => "KC_UP",
+            Key::Down => "KC_DOWN",
+            Key::Left => "KC_LEFT",
+            Key::Right => "KC_RIGHT",
+            Key::PageUp => "KC_PGUP",
+            Key::PageDown => "KC_PGDN",
+
+            Key::VolumeUp => "KC_VOLU",
+            Key::VolumeDown => "KC_VOLD",
+            Key::VolumeMute => "KC_MUTE",
+            Key::BrightnessUp => "KC_BRIGHTNESS_UP",
+            Key::BrightnessDown => "KC_BRIGHTNESS_DOWN",
+
+            Key::Aigu => "RALT(KC_QUOTE)",
+            Key::Grave => "RALT(KC_GRAVE)",
+            Key::Cedille => "RALT(KC_COMMA)",
+            Key::Circonflex => "RALT(LSFT(KC_6))",
+            Key::Trema => "RALT(LSFT(KC_QUOTE))",
+            Key::Euro => "RALT(KC_EQUAL)",
+
+            Key::Esc => "KC_ESC",
+            Key::Tab => "KC_TAB",
+            Key::Space => "KC_SPACE",
+            Key::Backspace => "KC_BSPC",
+            Key::Enter => "KC_ENTER",
+            Key::Meta => "KC_LGUI",
+            Key::Shift => "KC_LSFT",
+            Key::Ctrl => "KC_LCTL",
+            Key::Alt => "KC_LALT",
+
+            Key::MouseWheelUp => "QK_MOUSE_WHEEL_UP",
+            Key::MouseWheelDown => "QK_MOUSE_WHEEL_DOWN",
+
+            Key::CapsWord => "QK_CAPS_WORD_TOGGLE",
+
+            Key::Bootloader => "QK_BOOT",
+
+            Key::RgbToggle => self.rgb("UG_TOGG", "RM_TOGG"),
+            Key::RgbEffectNext => self.rgb("UG_NEXT", "RM_NEXT"),
+            Key::RgbEffectPrev => self.rgb("UG_PREV", "RM_PREV"),
+            Key::RgbHueUp => self.rgb("UG_HUEU", "RM_HUEU"),
+            Key::RgbHueDown => self.rgb("UG_HUED", "RM_HUED"),
+            Key::RgbSaturationUp => self.rgb("UG_SATU", "RM_SATU"),
+            Key::RgbSaturationDown => self.rgb("UG_SATD", "RM_SATD"),
+            Key::RgbBrightnessUp => self.rgb("UG_VALU", "RM_VALU"),
+            Key::RgbBrightnessDown => self.rgb("UG_VALD", "RM_VALD"),
+
+            Key::BacklightToggle => "BL_TOGG",
+            Key::BacklightUp => "BL_UP",
+            Key::BacklightDown => "BL_DOWN",
+
+            Key::NotAllowed => "KC_NO",
+            Key::Unassigned => "KC_TRNS",
+
+            // No appropriate keycode.
+            Key::Bt0 | Key::Bt1 | Key::BtClear => "KC_NO",
+            Key::ExtPowerToggle | Key::ExtPowerOn | Key::ExtPowerOff => "KC_NO",
+        }
+    }
+
+    /// Whether the key is a basic keycode, the only kind `MT` and `LT` can tap.
+    fn is_basic(&self, key: &TapKey) -> bool {
+        match key {
+            TapKey::Key(key) => {
+                let keycode = self.kc(key);
+                keycode.starts_with("KC_") && !keycode.contains('(')
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the key is a basic keycode, optionally with modifiers,
+    /// the only kind `tap_code16` and key overrides can send.
+    fn is_modified_basic(&self, key: &KeyDef) -> bool {
+        match key {
+            KeyDef::Tap(TapKey::Key(key) | TapKey::Modified(_, key)) => {
+                let keycode = self.kc(key);
+                let basic = keycode.rsplit('(').next().unwrap_or(keycode);
+                basic.starts_with("KC_")
+            }
+            _ => false,
+        }
+    }
+
+    /// The RGB underglow or RGB matrix keycode.
+    fn rgb(&self, underglow: &'static str, matrix: &'static str) -> &'static str {
+        if self.rgb_matrix { matrix } else { underglow }
+    }
+
+    fn tk(&self, key: &TapKey, layer_ref: &dyn Fn(&str) -> String) -> String {
+        match key {
+            TapKey::Key(key) => self.kc(key).to_string(),
+            TapKey::Layer(layer) => format!("MO({})", layer_ref(layer)),
+            TapKey::ToggleLayer(layer) => format!("TG({})", layer_ref(layer)),
+            TapKey::OneShotMod(modifier) => {
+                let modifier = mc(modifier);
+                format!("OSM(MOD_{modifier})")
+            }
+            TapKey::OneShotLayer(layer) => {
+                format!("OSL({})", layer_ref(layer))
+            }
+            TapKey::Modified(modifiers, key) => {
+                let mut s = String::new();
+                let mut depth = 0;
+                for modifier in modifiers {
+                    let modifier = mc(modifier);
+                    s.push_str(&format!("{modifier}("));
+                    depth += 1;
+                }
+                s.push_str(self.kc(key));
+                for _ in 0..depth {
+                    s.push(')');
+                }
+                s
+            }
+        }
+    }
+
+    fn hk(&self, hold: &HoldKey, tap: &TapKey, layer_ref: &dyn Fn(&str) -> String) -> String {
+        let tap = self.tk(tap, layer_ref);
+        match hold {
+            HoldKey::Layer(layer) => format!("LT({}, {tap})", layer_ref(layer)),
+            HoldKey::Modifier(modifier) => {
+                let modifier = mc(modifier);
+                format!("MT(MOD_{modifier}, {tap})")
+            }
+        }
+    }
+
+    fn kd(&self, keydef: &KeyDef) -> String {
+        self.keycode(keydef, &|layer| layer.to_string())
+    }
+
+    /// The keycode for a key, with layers referred to by `layer_ref`.
+    /// In `keymap.c` that's the layer's name, from the `layers` enum.
+    pub(super) fn keycode(&self, keydef: &KeyDef, layer_ref: &dyn Fn(&str) -> String) -> String {
+        match keydef {
+            KeyDef::Tap(key) => self.tk(key, layer_ref),
+            KeyDef::TapHold(tap, hold) => self.hk(hold, tap, layer_ref),
+        }
+    }
+
+    fn ko(&self, key_override: &KeyOverride) -> String {
+        let KeyOverride {
+            mods,
+            input,
+            output,
+            keep_mods,
+            layers,
+            ..
+        } = key_override;
+        let trigger_mods = mod_mask(mods);
+        let layers = layers
+            .iter()
+            .map(|layer| format!("(1 << {layer})"))
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        if keep_mods.is_empty() {
+            format!(
+                "ko_make_with_layers({trigger_mods}, {}, {}, {layers})",
+                self.kd(input),
+                self.kd(output)
+            )
+        } else {
+            // There's no helper for setting the suppressed mods.
+            format!(
+                "{{ .trigger = {}, .trigger_mods = {trigger_mods}, .layers = {}, .negative_mod_mask = 0, .suppressed_mods = {}, .replacement = {}, .options = ko_options_default, .custom_action = NULL, .context = NULL, .enabled = NULL }}",
+                self.kd(input),
+                layers,
+                mod_mask(&suppressed_mods(key_override)),
+                self.kd(output),
+            )
+        }
+    }
+
+    /// The condition for a key override on a tapped hold-tap key.
+    /// Like `key_overrides`, all of the trigger modifiers must be held.
+    fn tap_override(&self, key_override: &KeyOverride) -> String {
+        let mods = key_override
+            .mods
+            .iter()
+            .map(|modifier| format!("(mods & {})", mod_mask(std::slice::from_ref(modifier))));
+        let layers = key_override
+            .layers
+            .iter()
+            .map(|layer| format!("layer == {layer}"))
+            .collect::<Vec<_>>()
+            .join(" || ");
+        [
+            format!("keycode == {}", self.kd(&key_override.input)),
+            "record->event.pressed".to_string(),
+            "record->tap.count".to_string(),
+        ]
+        .into_iter()
+        .chain(mods)
+        .chain([format!("({layers})")])
+        .collect::<Vec<_>>()
+        .join(" && ")
+    }
+
+    /// An encoder's binding on a layer, see [`Layer::encoders`].
+    fn encoder(&self, encoder: Option<&Encoder>) -> String {
+        match encoder {
+            Some(Encoder { ccw, cw }) => {
+                format!("ENCODER_CCW_CW({}, {})", self.kd(ccw), self.kd(cw))
+            }
+            None => "ENCODER_CCW_CW(KC_TRNS, KC_TRNS)".to_string(),
+        }
     }
 }
 
-fn mc(key: &Modifier) -> &'static str {
+pub(super) fn mc(key: &Modifier) -> &'static str {
     match key {
         Modifier::Alt => "LALT",
         Modifier::Meta => "LGUI",
@@ -181,49 +583,37 @@ fn mc(key: &Modifier) -> &'static str {
     }
 }
 
-fn tk(key: &TapKey) -> String {
-    match key {
-        TapKey::Key(key) => kc(key).to_string(),
-        TapKey::Layer(layer) => format!("MO({layer})"),
-        TapKey::ToggleLayer(layer) => format!("TG({layer})"),
-        TapKey::OneShotMod(modifier) => {
-            let modifier = mc(modifier);
-            format!("OSM(MOD_{modifier})")
-        }
-        TapKey::OneShotLayer(layer) => {
-            format!("OSL({layer})")
-        }
-        TapKey::Modified(modifiers, key) => {
-            let mut s = String::new();
-            let mut depth = 0;
-            for modifier in modifiers {
-                let modifier = mc(modifier);
-                s.push_str(&format!("{modifier}("));
-                depth += 1;
-            }
-            s.push_str(kc(key));
-            for _ in 0..depth {
-                s.push(')');
-            }
-            s
-        }
+/// Mod mask matching any of the modifiers.
+fn mod_mask(mods: &[Modifier]) -> String {
+    let masks: Vec<_> = mods
+        .iter()
+        .map(|modifier| match modifier {
+            Modifier::Alt => "MOD_MASK_ALT",
+            Modifier::Meta => "MOD_MASK_GUI",
+            Modifier::Ctrl => "MOD_MASK_CTRL",
+            Modifier::Shift => "MOD_MASK_SHIFT",
+        })
+        .collect();
+    match masks[..] {
+        [] => "0".to_string(),
+        [mask] => mask.to_string(),
+        _ => format!("({})", masks.join(" | ")),
     }
 }
 
-fn hk(hold: &HoldKey, tap: &TapKey) -> String {
-    let tap = tk(tap);
-    match hold {
-        HoldKey::Layer(layer) => format!("LT({layer}, {tap})"),
-        HoldKey::Modifier(modifier) => {
-            let modifier = mc(modifier);
-            format!("MT(MOD_{modifier}, {tap})")
-        }
-    }
+/// Key overrides either on hold-tap keys or on other keys.
+fn overrides(keymap: &KeyMap, hold_tap: bool) -> impl Iterator<Item = &KeyOverride> {
+    keymap
+        .overrides()
+        .filter(move |key_override| matches!(key_override.input, KeyDef::TapHold(..)) == hold_tap)
 }
 
-fn kd(keydef: &KeyDef) -> String {
-    match keydef {
-        KeyDef::Tap(key) => tk(key),
-        KeyDef::TapHold(tap, hold) => hk(hold, tap),
-    }
+/// The trigger modifiers that aren't sent with the output.
+pub(super) fn suppressed_mods(key_override: &KeyOverride) -> Vec<Modifier> {
+    key_override
+        .mods
+        .iter()
+        .filter(|modifier| !key_override.keep_mods.contains(modifier))
+        .cloned()
+        .collect()
 }
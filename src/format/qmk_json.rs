@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use super::{Format, QMK};
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::{Encoder, KeyMap},
+};
+
+/// A QMK `keymap.json`, as used by the QMK Configurator and `qmk json2c`.
+/// Combos and key overrides can't be expressed in it, so they're left out.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "Options")]
+pub struct QMKJSON {
+    /// The keyboard, e.g. `crkbd/rev1`.
+    pub keyboard: String,
+
+    /// The keymap's name.
+    pub keymap: String,
+
+    /// Options shared with the `keymap.c` target.
+    #[serde(flatten)]
+    pub qmk: QMK,
+}
+impl Default for QMKJSON {
+    fn default() -> Self {
+        Self {
+            keyboard: String::new(),
+            keymap: "default".to_string(),
+            qmk: QMK::default(),
+        }
+    }
+}
+impl TryFrom<Options> for QMKJSON {
+    type Error = String;
+
+    fn try_from(options: Options) -> Result<Self, Self::Error> {
+        if let Some(key) = options.unknown.keys().next() {
+            return Err(format!("unknown field `{key}`"));
+        }
+        let default = Self::default();
+        Ok(Self {
+            keyboard: options.keyboard.unwrap_or(default.keyboard),
+            keymap: options.keymap.unwrap_or(default.keymap),
+            qmk: options.qmk,
+        })
+    }
+}
+impl QMKJSON {
+    /// Options from the `QMK_KEYBOARD` and `QMK_KEYMAP` environment variables,
+    /// and those of [`QMK::from_env`], if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            keyboard: std::env::var("QMK_KEYBOARD").unwrap_or(default.keyboard),
+            keymap: std::env::var("QMK_KEYMAP").unwrap_or(default.keymap),
+            qmk: QMK::from_env(),
+        }
+    }
+}
+
+/// The options as written, with any keys that aren't options,
+/// since the flattened QMK options can't deny unknown fields.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Options {
+    keyboard: Option<String>,
+    keymap: Option<String>,
+    #[serde(flatten)]
+    qmk: QMK,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+impl Format for QMKJSON {
+    fn format(&self, keymap: &KeyMap) -> String {
+        // Layers don't have names in `keymap.json`, only their index.
+        let layer_ref = |name: &str| match keymap.layers.iter().position(|l| l.name == name) {
+            Some(index) => index.to_string(),
+            None => name.to_string(),
+        };
+        let layers: Vec<Vec<String>> = keymap
+            .layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .layout
+                    .iter()
+                    .map(|key| self.qmk.keycode(key, &layer_ref))
+                    .collect()
+            })
+            .collect();
+
+        let mut json = serde_json::json!({
+            "version": 1,
+            "notes": "Generated by kbl",
+            "keyboard": self.keyboard,
+            "keymap": self.keymap,
+            "layout": self.qmk.layout_macro,
+            "layers": layers,
+        });
+        let encoders = keymap.encoder_count();
+        if encoders > 0 {
+            json["encoders"] = keymap
+                .layers
+                .iter()
+                .map(|layer| {
+                    (0..encoders)
+                        .map(|i| match layer.encoders.get(&i) {
+                            Some(Encoder { ccw, cw }) => serde_json::json!({
+                                "ccw": self.qmk.keycode(ccw, &layer_ref),
+                                "cw": self.qmk.keycode(cw, &layer_ref),
+                            }),
+                            None => serde_json::json!({ "ccw": "KC_TRNS", "cw": "KC_TRNS" }),
+                        })
+                        .collect::<serde_json::Value>()
+                })
+                .collect();
+        }
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = self.qmk.check_keys(keymap);
+        if self.keyboard.is_empty() {
+            diagnostics.push(Diagnostic::error(
+                "keymap.json needs a keyboard, set it with QMK_KEYBOARD",
+            ));
+        }
+        if keymap.combos().next().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "Combos can't be expressed in keymap.json, so they're left out",
+            ));
+        }
+        if keymap.overrides().next().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "Key overrides can't be expressed in keymap.json, so they're left out",
+            ));
+        }
+        diagnostics
+    }
+}
@@ -0,0 +1,431 @@
+use std::fmt::Write;
+
+use super::{
+    Format, check_matrix, layer_index, matrix_positions, parse_matrix, send_mods,
+    transparent_outputs, typed_with,
+};
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::{Encoder, KeyMap, KeyOverride},
+    keys::{HoldKey, Key, KeyDef, Modifier, TapKey},
+};
+
+/// The keymap sections of an RMK `keyboard.toml`:
+/// `[layout]`, `[behavior.tap_hold]`, `[behavior.combo]` and `[behavior.fork]`.
+/// Key overrides are RMK forks.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RMK {
+    /// The combo `timeout` in ms.
+    pub combo_timeout: u16,
+
+    /// The tap-hold `hold_timeout` in ms.
+    pub hold_timeout: u16,
+
+    /// See [`Vial::matrix`](super::Vial::matrix).
+    pub matrix: Vec<(usize, usize)>,
+}
+impl Default for RMK {
+    fn default() -> Self {
+        Self {
+            combo_timeout: 45,
+            hold_timeout: 200,
+            matrix: vec![],
+        }
+    }
+}
+impl RMK {
+    /// Options from the `RMK_COMBO_TIMEOUT`, `RMK_HOLD_TIMEOUT` and
+    /// `RMK_MATRIX` (space-separated `row,column` pairs) environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            combo_timeout: std::env::var("RMK_COMBO_TIMEOUT")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.combo_timeout),
+            hold_timeout: std::env::var("RMK_HOLD_TIMEOUT")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.hold_timeout),
+            matrix: std::env::var("RMK_MATRIX")
+                .map(|v| parse_matrix(&v))
+                .unwrap_or(default.matrix),
+        }
+    }
+}
+
+impl Format for RMK {
+    fn format(&self, keymap: &KeyMap) -> String {
+        let positions = matrix_positions(&self.matrix, keymap);
+        let rows = positions.iter().map(|(row, _)| row + 1).max().unwrap_or(0);
+        let cols = positions.iter().map(|(_, col)| col + 1).max().unwrap_or(0);
+        let action = |key: &KeyDef| quoted(&kd(key, keymap));
+
+        let mut toml = String::new();
+        writeln!(toml, "[layout]").unwrap();
+        writeln!(toml, "rows = {rows}").unwrap();
+        writeln!(toml, "cols = {cols}").unwrap();
+        writeln!(toml, "layers = {}", keymap.layers.len()).unwrap();
+        writeln!(toml, "keymap = [").unwrap();
+        for layer in &keymap.layers {
+            // Matrix positions without a key do nothing.
+            let mut matrix = vec![vec![quoted("No"); cols]; rows];
+            for (key, (row, col)) in layer.layout.iter().zip(&positions) {
+                matrix[*row][*col] = action(key);
+            }
+            writeln!(toml, "  # {}", layer.name).unwrap();
+            writeln!(toml, "  [").unwrap();
+            for row in matrix {
+                writeln!(toml, "    [{}],", row.join(", ")).unwrap();
+            }
+            writeln!(toml, "  ],").unwrap();
+        }
+        writeln!(toml, "]").unwrap();
+
+        let encoders = keymap.encoder_count();
+        if encoders > 0 {
+            writeln!(toml, "encoder_map = [").unwrap();
+            for layer in &keymap.layers {
+                let bindings: Vec<_> = (0..encoders)
+                    .map(|i| match layer.encoders.get(&i) {
+                        Some(Encoder { ccw, cw }) => format!("[{}, {}]", action(cw), action(ccw)),
+                        None => format!("[{}, {}]", quoted("Trns"), quoted("Trns")),
+                    })
+                    .collect();
+                writeln!(toml, "  [{}], # {}", bindings.join(", "), layer.name).unwrap();
+            }
+            writeln!(toml, "]").unwrap();
+        }
+
+        writeln!(toml).unwrap();
+        writeln!(toml, "[behavior.tap_hold]").unwrap();
+        writeln!(toml, "hold_timeout = \"{}ms\"", self.hold_timeout).unwrap();
+
+        let combos = combos(keymap);
+        if !combos.is_empty() {
+            writeln!(toml).unwrap();
+            writeln!(toml, "[behavior.combo]").unwrap();
+            writeln!(toml, "timeout = \"{}ms\"", self.combo_timeout).unwrap();
+            writeln!(toml, "combos = [").unwrap();
+            for (layer, inputs, output) in combos {
+                let actions: Vec<_> = inputs.into_iter().map(action).collect();
+                writeln!(
+                    toml,
+                    "  {{ actions = [{}], output = {}, layer = {layer} }},",
+                    actions.join(", "),
+                    action(output)
+                )
+                .unwrap();
+            }
+            writeln!(toml, "]").unwrap();
+        }
+
+        if keymap.overrides().next().is_some() {
+            writeln!(toml).unwrap();
+            writeln!(toml, "[behavior.fork]").unwrap();
+            writeln!(toml, "forks = [").unwrap();
+            for key_override in keymap.overrides() {
+                let KeyOverride {
+                    mods,
+                    input,
+                    output,
+                    keep_mods,
+                    ..
+                } = key_override;
+                write!(
+                    toml,
+                    "  {{ trigger = {}, negative_output = {}, positive_output = {}, match_any = {}",
+                    action(input),
+                    action(input),
+                    action(output),
+                    quoted(&either_side(mods)),
+                )
+                .unwrap();
+                if !keep_mods.is_empty() {
+                    write!(
+                        toml,
+                        ", kept_modifiers = {}",
+                        quoted(&either_side(keep_mods))
+                    )
+                    .unwrap();
+                }
+                writeln!(toml, " }},").unwrap();
+            }
+            writeln!(toml, "]").unwrap();
+        }
+        toml.trim_end().to_string()
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = transparent_outputs(keymap);
+        diagnostics.extend(check_matrix(&self.matrix, keymap));
+        for (location, key) in keymap.located_keys() {
+            let (KeyDef::Tap(tap) | KeyDef::TapHold(tap, _)) = key;
+            if let Some(key) = tap.key()
+                && rk(key) == "No"
+                && *key != Key::NotAllowed
+            {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: {key:?} isn't supported by RMK, so it does nothing"
+                )));
+            }
+        }
+
+        let combos = combos(keymap);
+        if combos.len() > MAX_COMBOS {
+            diagnostics.push(Diagnostic::warning(format!(
+                "There are {} combos (one per layer they're active on), more than RMK's default `combo_max_num` of {MAX_COMBOS}",
+                combos.len()
+            )));
+        }
+        for (location, key_override) in keymap.located_overrides() {
+            if key_override.mods.len() > 1 {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: RMK forks trigger on any of their modifiers, not only all of them"
+                )));
+            }
+            // Forks match the trigger key wherever it is.
+            for layer in &keymap.layers {
+                if !key_override.layers.contains(&layer.name)
+                    && layer.layout.contains(&key_override.input)
+                {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "{location}: RMK forks are active on every layer, so this also applies on {}",
+                        layer.name
+                    )));
+                }
+            }
+        }
+        diagnostics
+    }
+}
+
+/// RMK's default `combo_max_num`.
+const MAX_COMBOS: usize = 8;
+
+/// RMK combos match actions rather than key positions, so each combo is
+/// repeated for each layer it's active on, with the keys on that layer.
+/// Transparent keys are looked up on the base layer.
+/// Positions outside a layer are left out; `KeyMap::validate` reports them.
+fn combos(keymap: &KeyMap) -> Vec<(usize, Vec<&KeyDef>, &KeyDef)> {
+    keymap
+        .combos()
+        .flat_map(|combo| {
+            combo.layers.iter().filter_map(move |name| {
+                let index = layer_index(keymap, name);
+                let layer = keymap.layers.get(index)?;
+                let base = keymap.layers.first()?;
+                let inputs = combo
+                    .inputs
+                    .iter()
+                    .map(|position| match layer.layout.get(*position)? {
+                        KeyDef::Tap(TapKey::Key(Key::Unassigned)) => base.layout.get(*position),
+                        key => Some(key),
+                    })
+                    .collect::<Option<_>>()?;
+                Some((index, inputs, &combo.output))
+            })
+        })
+        .collect()
+}
+
+fn quoted(action: &str) -> String {
+    format!("\"{action}\"")
+}
+
+fn kd(keydef: &KeyDef, keymap: &KeyMap) -> String {
+    match keydef {
+        KeyDef::Tap(tap) => tk(tap, keymap),
+        // RMK's `LT` and `MT` only take plain keys.
+        KeyDef::TapHold(TapKey::Key(key), hold) if typed_with(key).is_empty() => {
+            let tap = rk(key);
+            match hold {
+                HoldKey::Layer(layer) => format!("LT({}, {tap})", layer_index(keymap, layer)),
+                HoldKey::Modifier(modifier) => format!("MT({tap}, {})", mc(modifier)),
+            }
+        }
+        KeyDef::TapHold(tap, hold) => {
+            let hold = match hold {
+                HoldKey::Layer(layer) => format!("MO({})", layer_index(keymap, layer)),
+                HoldKey::Modifier(modifier) => mc(modifier).to_string(),
+            };
+            format!("TH({}, {hold})", tk(tap, keymap))
+        }
+    }
+}
+
+fn tk(key: &TapKey, keymap: &KeyMap) -> String {
+    match key {
+        TapKey::Key(key) => with_mods(rk(key), send_mods(&[], key, mc, "RAlt")),
+        TapKey::Layer(layer) => format!("MO({})", layer_index(keymap, layer)),
+        TapKey::ToggleLayer(layer) => format!("TG({})", layer_index(keymap, layer)),
+        TapKey::OneShotMod(modifier) => format!("OSM({})", mc(modifier)),
+        TapKey::OneShotLayer(layer) => format!("OSL({})", layer_index(keymap, layer)),
+        TapKey::Modified(modifiers, key) => {
+            with_mods(rk(key), send_mods(modifiers, key, mc, "RAlt"))
+        }
+    }
+}
+
+/// A key sent with modifiers, `WM(key, mods)`.
+fn with_mods(name: &str, mods: Vec<&str>) -> String {
+    if mods.is_empty() {
+        name.to_string()
+    } else {
+        format!("WM({name}, {})", mods.join("|"))
+    }
+}
+
+fn mc(modifier: &Modifier) -> &'static str {
+    match modifier {
+        Modifier::Alt => "LAlt",
+        Modifier::Meta => "LGui",
+        Modifier::Ctrl => "LCtrl",
+        Modifier::Shift => "LShift",
+    }
+}
+
+/// Modifiers on either side of the keyboard, e.g. `LShift|RShift`.
+fn either_side(mods: &[Modifier]) -> String {
+    mods.iter()
+        .map(|modifier| {
+            let left = mc(modifier);
+            format!("{left}|R{}", &left[1..])
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// RMK's keycode for a key, sent with the modifiers it's [`typed_with`].
+fn rk(key: &Key) -> &'static str {
+    match key {
+        Key::A => "A",
+        Key::B => "B",
+        Key::C => "C",
+        Key::D => "D",
+        Key::E => "E",
+        Key::F => "F",
+        Key::G => "G",
+        Key::H => "H",
+        Key::I => "I",
+        Key::J => "J",
+        Key::K => "K",
+        Key::L => "L",
+        Key::M => "M",
+        Key::N => "N",
+        Key::O => "O",
+        Key::P => "P",
+        Key::Q => "Q",
+        Key::R => "R",
+        Key::S => "S",
+        Key::T => "T",
+        Key::U => "U",
+        Key::V => "V",
+        Key::W => "W",
+        Key::X => "X",
+        Key::Y => "Y",
+        Key::Z => "Z",
+
+        Key::Num0 => "Kc0",
+        Key::Num1 => "Kc1",
+        Key::Num2 => "Kc2",
+        Key::Num3 => "Kc3",
+        Key::Num4 => "Kc4",
+        Key::Num5 => "Kc5",
+        Key::Num6 => "Kc6",
+        Key::Num7 => "Kc7",
+        Key::Num8 => "Kc8",
+        Key::Num9 => "Kc9",
+
+        Key::Pipe => "Backslash",
+        Key::Plus => "Equal",
+        Key::Minus => "Minus",
+        Key::Equal => "Equal",
+        Key::Comma => "Comma",
+        Key::Period => "Dot",
+        Key::Colon => "Semicolon",
+        Key::Caret => "Kc6",
+        Key::Dollar => "Kc4",
+        Key::Percent => "Kc5",
+        Key::Semicolon => "Semicolon",
+        Key::Question => "Slash",
+        Key::Exclamation => "Kc1",
+        Key::Octothorpe => "Kc3",
+        Key::Ampersand => "Kc7",
+        Key::Arobase => "Kc2",
+        Key::Asterisk => "Kc8",
+        Key::Slash => "Slash",
+        Key::Tilde => "Grave",
+        Key::Backtick => "Grave",
+        Key::LessThan => "Comma",
+        Key::GreaterThan => "Dot",
+        Key::ParensRight => "Kc0",
+        Key::ParensLeft => "Kc9",
+        Key::BracketRight => "RightBracket",
+        Key::BracketLeft => "LeftBracket",
+        Key::BraceRight => "RightBracket",
+        Key::BraceLeft => "LeftBracket",
+        Key::Underscore => "Minus",
+        Key::Apostrophe => "Quote",
+        Key::Backslash => "Backslash",
+        Key::Quote => "Quote",
+
+        Key::Up => "Up",
+        Key::Down => "Down",
+        Key::Left => "Left",
+        Key::Right => "Right",
+        Key::PageUp => "PageUp",
+        Key::PageDown => "PageDown",
+
+        Key::VolumeUp => "AudioVolUp",
+        Key::VolumeDown => "AudioVolDown",
+        Key::VolumeMute => "AudioMute",
+        Key::BrightnessUp => "BrightnessUp",
+        Key::BrightnessDown => "BrightnessDown",
+
+        Key::Aigu => "Quote",
+        Key::Grave => "Grave",
+        Key::Cedille => "Comma",
+        Key::Circonflex => "Kc6",
+        Key::Trema => "Quote",
+        Key::Euro => "Equal",
+
+        Key::Esc => "Escape",
+        Key::Tab => "Tab",
+        Key::Space => "Space",
+        Key::Backspace => "Backspace",
+        Key::Enter => "Enter",
+        Key::Meta => "LGui",
+        Key::Shift => "LShift",
+        Key::Ctrl => "LCtrl",
+        Key::Alt => "LAlt",
+
+        Key::MouseWheelUp => "MouseWheelUp",
+        Key::MouseWheelDown => "MouseWheelDown",
+
+        Key::CapsWord => "CapsWordToggle",
+
+        // RMK's `User0`-`User7` switch BLE profiles, and `User10` clears the current one.
+        Key::Bt0 => "User0",
+        Key::Bt1 => "User1",
+        Key::BtClear => "User10",
+
+        Key::Bootloader => "Bootloader",
+
+        Key::NotAllowed => "No",
+        Key::Unassigned => "Trns",
+
+        // No appropriate keycode.
+        Key::RgbToggle
+        | Key::RgbEffectNext
+        | Key::RgbEffectPrev
+        | Key::RgbHueUp
+        | Key::RgbHueDown
+        | Key::RgbSaturationUp
+        | Key::RgbSaturationDown
+        | Key::RgbBrightnessUp
+        | Key::RgbBrightnessDown => "No",
+        Key::BacklightToggle | Key::BacklightUp | Key::BacklightDown => "No",
+        Key::ExtPowerToggle | Key::ExtPowerOn | Key::ExtPowerOff => "No",
+    }
+}
@@ -0,0 +1,248 @@
+use super::{
+    Format, QMK, check_matrix, layer_index, matrix_positions, parse_matrix,
+    qmk::{mc, suppressed_mods},
+};
+use crate::{
+    diagnostic::Diagnostic,
+    keymap::{Encoder, KeyMap, KeyOverride},
+    keys::{HoldKey, KeyDef, Modifier, TapKey},
+};
+
+use serde_json::{Value, json};
+
+/// A Vial `.vil` layout, which can be loaded onto a keyboard at runtime.
+/// Keycodes are QMK's, with layers referred to by their index.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Vial {
+    /// The keyboard's Vial UID, as in a `.vil` saved from Vial.
+    pub uid: u64,
+
+    /// The matrix `(row, column)` of each key in the layout, in order.
+    /// If empty, the keys' rows and columns in the layout are used.
+    pub matrix: Vec<(usize, usize)>,
+
+    /// The tapping term in ms, for the tap dances.
+    pub tapping_term: u16,
+
+    /// See [`QMK::rgb_matrix`].
+    pub rgb_matrix: bool,
+}
+impl Default for Vial {
+    fn default() -> Self {
+        let qmk = QMK::default();
+        Self {
+            uid: 0,
+            matrix: vec![],
+            tapping_term: qmk.tapping_term,
+            rgb_matrix: qmk.rgb_matrix,
+        }
+    }
+}
+impl Vial {
+    /// Options from the `VIAL_UID`, `VIAL_MATRIX` (space-separated `row,column` pairs),
+    /// `QMK_TAPPING_TERM` and `QMK_RGB_MATRIX` environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let qmk = QMK::from_env();
+        Self {
+            uid: std::env::var("VIAL_UID")
+                .map(|v| v.parse::<u64>().unwrap())
+                .unwrap_or(default.uid),
+            matrix: std::env::var("VIAL_MATRIX")
+                .map(|v| parse_matrix(&v))
+                .unwrap_or(default.matrix),
+            tapping_term: qmk.tapping_term,
+            rgb_matrix: qmk.rgb_matrix,
+        }
+    }
+}
+
+impl Format for Vial {
+    fn format(&self, keymap: &KeyMap) -> String {
+        let qmk = QMK {
+            rgb_matrix: self.rgb_matrix,
+            ..QMK::default()
+        };
+        let layer_ref = |name: &str| layer_index(keymap, name).to_string();
+        let keycode = |key: &KeyDef| qmk.keycode(key, &layer_ref);
+
+        // Vial's tap-hold keycodes only take basic keys,
+        // so other tap-holds are sent as tap dances.
+        let mut tap_dances: Vec<KeyDef> = vec![];
+        let mut vial_keycode = |key: &KeyDef| match key {
+            KeyDef::TapHold(TapKey::Key(tap), hold) => {
+                let tap = keycode(&KeyDef::Tap(TapKey::Key(tap.clone())));
+                match hold {
+                    HoldKey::Layer(layer) => format!("LT{}({tap})", layer_index(keymap, layer)),
+                    HoldKey::Modifier(modifier) => format!("{}_T({tap})", mc(modifier)),
+                }
+            }
+            KeyDef::TapHold(..) => {
+                let index = match tap_dances.iter().position(|td| td == key) {
+                    Some(index) => index,
+                    None => {
+                        tap_dances.push(key.clone());
+                        tap_dances.len() - 1
+                    }
+                };
+                format!("TD({index})")
+            }
+            KeyDef::Tap(_) => keycode(key),
+        };
+
+        let positions = matrix_positions(&self.matrix, keymap);
+        let rows = positions.iter().map(|(row, _)| row + 1).max().unwrap_or(0);
+        let cols = positions.iter().map(|(_, col)| col + 1).max().unwrap_or(0);
+        let layout: Vec<Vec<Vec<Value>>> = keymap
+            .layers
+            .iter()
+            .map(|layer| {
+                // Matrix positions without a key are -1.
+                let mut matrix = vec![vec![json!(-1); cols]; rows];
+                for (key, (row, col)) in layer.layout.iter().zip(&positions) {
+                    matrix[*row][*col] = json!(vial_keycode(key));
+                }
+                matrix
+            })
+            .collect();
+
+        let encoders = keymap.encoder_count();
+        let encoder_layout: Vec<Vec<[String; 2]>> = keymap
+            .layers
+            .iter()
+            .map(|layer| {
+                (0..encoders)
+                    .map(|i| match layer.encoders.get(&i) {
+                        Some(Encoder { ccw, cw }) => [vial_keycode(ccw), vial_keycode(cw)],
+                        None => ["KC_TRNS".to_string(), "KC_TRNS".to_string()],
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Combos look up their keys on the base layer, as with QMK.
+        let base = keymap.layers.first();
+        let combos: Vec<Vec<String>> = keymap
+            .combos()
+            .filter(|combo| combo.inputs.len() <= MAX_KEYS_PER_COMBO)
+            .map(|combo| {
+                let mut entry: Vec<_> = combo
+                    .inputs
+                    .iter()
+                    .map(
+                        |position| match base.and_then(|base| base.layout.get(*position)) {
+                            Some(key) => vial_keycode(key),
+                            None => "KC_NO".to_string(),
+                        },
+                    )
+                    .collect();
+                entry.resize(MAX_KEYS_PER_COMBO, "KC_NO".to_string());
+                entry.push(vial_keycode(&combo.output));
+                entry
+            })
+            .collect();
+
+        let key_overrides: Vec<Value> = keymap
+            .overrides()
+            .map(|key_override| {
+                let KeyOverride {
+                    mods,
+                    input,
+                    output,
+                    layers,
+                    ..
+                } = key_override;
+                let layers = layers
+                    .iter()
+                    .fold(0u32, |mask, layer| mask | 1 << layer_index(keymap, layer));
+                json!({
+                    "trigger": vial_keycode(input),
+                    "replacement": vial_keycode(output),
+                    "layers": layers,
+                    "trigger_mods": mod_bits(mods),
+                    "negative_mod_mask": 0,
+                    "suppressed_mods": mod_bits(&suppressed_mods(key_override)),
+                    // Enabled, with QMK's default activation options.
+                    "options": 0b1000_0111,
+                })
+            })
+            .collect();
+
+        let tap_dances: Vec<Value> = tap_dances
+            .iter()
+            .map(|key| {
+                let KeyDef::TapHold(tap, hold) = key else {
+                    unreachable!()
+                };
+                let hold = match hold {
+                    HoldKey::Layer(layer) => format!("MO({})", layer_index(keymap, layer)),
+                    HoldKey::Modifier(modifier) => format!("KC_{}", mc(modifier)),
+                };
+                json!([
+                    keycode(&KeyDef::Tap(tap.clone())),
+                    hold,
+                    "KC_NO",
+                    "KC_NO",
+                    self.tapping_term
+                ])
+            })
+            .collect();
+
+        let vil = json!({
+            "version": 1,
+            "uid": self.uid,
+            "layout": layout,
+            "encoder_layout": encoder_layout,
+            "layout_options": -1,
+            "macro": [],
+            "vial_protocol": 6,
+            "via_protocol": 9,
+            "tap_dance": tap_dances,
+            "combo": combos,
+            "key_override": key_overrides,
+        });
+        serde_json::to_string_pretty(&vil).unwrap()
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let qmk = QMK {
+            rgb_matrix: self.rgb_matrix,
+            ..QMK::default()
+        };
+        let mut diagnostics = qmk.check_keys(keymap);
+        if self.uid == 0 {
+            diagnostics.push(Diagnostic::warning(
+                "The layout has no Vial UID, so Vial won't match it to the keyboard. Set it with VIAL_UID",
+            ));
+        }
+        diagnostics.extend(check_matrix(&self.matrix, keymap));
+        if keymap.combos().next().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "Vial combos match keycodes rather than key positions, and are active on every layer",
+            ));
+        }
+        for (location, combo) in keymap.located_combos() {
+            if combo.inputs.len() > MAX_KEYS_PER_COMBO {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: The combo has more than {MAX_KEYS_PER_COMBO} keys, which Vial doesn't support, so it's left out"
+                )));
+            }
+        }
+        diagnostics
+    }
+}
+
+const MAX_KEYS_PER_COMBO: usize = 4;
+
+/// QMK's 8-bit mod mask, matching either side of each modifier.
+fn mod_bits(mods: &[Modifier]) -> u8 {
+    mods.iter().fold(0, |mask, modifier| {
+        mask | match modifier {
+            Modifier::Ctrl => 0x11,
+            Modifier::Shift => 0x22,
+            Modifier::Alt => 0x44,
+            Modifier::Meta => 0x88,
+        }
+    })
+}
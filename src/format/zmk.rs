@@ -1,53 +1,117 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
 use genco::prelude::*;
 
 use crate::{
-    keymap::{KeyMap, Layer, Shifted},
+    diagnostic::Diagnostic,
+    keymap::{Combo, Encoder, KeyMap, KeyOverride, Layer, Location, Section},
     keys::{HoldKey, Key, KeyDef, Modifier, TapKey},
 };
 
-use super::Format;
+use super::{Format, transparent_outputs};
 
+/// A ZMK `.keymap`, or with `tree` a user config repo.
 #[allow(clippy::upper_case_acronyms)]
-pub struct ZMK;
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ZMK {
+    /// The combo `timeout-ms`.
+    pub combo_timeout: u16,
+
+    /// The board to build for in `build.yaml`.
+    pub board: String,
+
+    /// The shields to build for in `build.yaml`, if any.
+    pub shields: Vec<String>,
+}
+impl Default for ZMK {
+    fn default() -> Self {
+        Self {
+            combo_timeout: 45,
+            board: "nice_nano_v2".to_string(),
+            shields: vec![],
+        }
+    }
+}
+impl ZMK {
+    /// Options from the `ZMK_COMBO_TIMEOUT`, `ZMK_BOARD`
+    /// and `ZMK_SHIELDS` (comma-separated) environment variables, if set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            combo_timeout: std::env::var("ZMK_COMBO_TIMEOUT")
+                .map(|v| v.parse::<u16>().unwrap())
+                .unwrap_or(default.combo_timeout),
+            board: std::env::var("ZMK_BOARD").unwrap_or(default.board),
+            shields: std::env::var("ZMK_SHIELDS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|shield| shield.trim().to_string())
+                .filter(|shield| !shield.is_empty())
+                .collect(),
+        }
+    }
+}
 
 impl Format for ZMK {
-    fn format(keymap: &KeyMap) -> String {
-        let combo_timeout = std::env::var("ZMK_COMBO_TIMEOUT")
-            .map(|v| v.parse::<u16>().unwrap())
-            .unwrap_or(45);
+    fn format(&self, keymap: &KeyMap) -> String {
+        let combo_timeout = self.combo_timeout;
 
-        let include = &keymap.include;
+        let include = |section| keymap.include("zmk", section, Section::Behaviors);
+        let header = include(Section::Header);
+        let behaviors = include(Section::Behaviors);
+        let root = include(Section::Root);
+        let footer = include(Section::Footer);
+        let encoders = keymap.encoder_count();
         let tokens: Tokens<C> = quote! {
             #include <behaviors.dtsi>
             #include <dt-bindings/zmk/keys.h>
             #include <dt-bindings/zmk/bt.h>
+            #include <dt-bindings/zmk/rgb.h>
+            #include <dt-bindings/zmk/backlight.h>
+            #include <dt-bindings/zmk/ext_power.h>
 
             $(for (i, Layer { name, .. }) in keymap.layers.iter().enumerate() join($['\r']) =>
                 #define $name $i
             )
 
+            $header
+
             / {
                 behaviors {
-                    $include
+                    $behaviors
 
-                    $(for Shifted { name, input, output } in keymap.shifts() join($['\r']) {
+                    $(for (KeyOverride { name, output, mods, keep_mods, .. }, binding) in morphs(keymap) join($['\r']) {
                         $name: $name {
                             compatible = "zmk,behavior-mod-morph";
                             #binding-cells = <0>;
-                            bindings = <$(kd(input))>, <$(kd(output))>;
-                            mods = <(MOD_LSFT|MOD_RSFT)>;
+                            bindings = <$binding>, <$(kd(output))>;
+                            mods = <$(mod_flags(mods))>;
+                            $(if !keep_mods.is_empty() {
+                                keep-mods = <$(mod_flags(keep_mods))>;
+                            })
+                        };
+                    })
+
+                    $(for (name, (flavor, hold)) in hold_taps(keymap) join($['\r']) {
+                        $(name)_ht: $(name)_ht {
+                            compatible = "zmk,behavior-hold-tap";
+                            #binding-cells = <2>;
+                            flavor = $(quoted(flavor));
+                            tapping-term-ms = <200>;
+                            bindings = <$hold>, <&$name>;
                         };
                     })
                 };
 
                 combos {
                     compatible = "zmk,combos";
-                    $(for ComboDef { name, positions, output, layer, shifts } in combos(&keymap.layers) join($['\r']) =>
+                    $(for ComboDef { name, positions, output, layers, overrides } in combos(keymap) join($['\r']) =>
                         $name {
                             timeout-ms = <$combo_timeout>;
                             key-positions = <$positions>;
-                            bindings = <$(skd(output, shifts))>;
-                            layers = <$layer>;
+                            bindings = <$(skd(output, overrides.iter().copied()))>;
+                            layers = <$layers>;
                         };
                     )
                 };
@@ -55,49 +119,285 @@ impl Format for ZMK {
                 keymap {
                     compatible = "zmk,keymap";
 
-                    $(for Layer { name, layout, shifts, .. } in &keymap.layers join($['\r']) =>
+                    $(for Layer { name, layout, encoders: bindings, .. } in &keymap.layers join($['\r']) =>
                         $name {
                             bindings = <
                                 $(for key in layout join(  ) =>
-                                    $(skd(key, shifts))
+                                    $(skd(key, keymap.overrides_on(name)))
                                 )
                             >;
+                            $(if encoders > 0 {
+                                sensor-bindings = <$(for i in 0..encoders join( ) => $(sensor(bindings.get(&i))))>;
+                            })
                         };
                     )
                 };
+
+                $root
             };
+
+            $footer
         };
         tokens.to_file_string().unwrap()
     }
+
+    fn tree(&self, keymap: &KeyMap) -> Option<Vec<(PathBuf, String)>> {
+        let ZMK { board, shields, .. } = self;
+
+        // The keymap and config are named after the shield
+        // (without the split half suffix) or the board.
+        let name = shields
+            .first()
+            .map(|shield| shield.trim_end_matches("_left").trim_end_matches("_right"))
+            .unwrap_or(board);
+
+        Some(vec![
+            ("build.yaml".into(), build(board, shields)),
+            ("config/west.yml".into(), WEST_MANIFEST.to_string()),
+            (format!("config/{name}.keymap").into(), self.format(keymap)),
+            (format!("config/{name}.conf").into(), conf(keymap)),
+        ])
+    }
+
+    fn check(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = self.check_tree(keymap);
+        // ZMK allocates combos per key position across all layers.
+        // The tree's `.conf` raises these limits as needed.
+        for (position, count) in combos_per_key(keymap) {
+            if count > MAX_COMBOS_PER_KEY {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "Pos({position}) ({:?} on the base layer) is used by {count} combos, more than ZMK's default CONFIG_ZMK_COMBO_MAX_COMBOS_PER_KEY of {MAX_COMBOS_PER_KEY}",
+                    keymap
+                        .layers
+                        .first()
+                        .and_then(|base| base.glyphs.get(position))
+                        .unwrap_or(&' '),
+                )));
+            }
+        }
+        for (location, _) in keymap.located_keys() {
+            if let Location::Combo { inputs, .. } = location
+                && inputs.len() > MAX_KEYS_PER_COMBO
+            {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: The combo has {} keys, more than ZMK's default CONFIG_ZMK_COMBO_MAX_KEYS_PER_COMBO of {MAX_KEYS_PER_COMBO}",
+                    inputs.len()
+                )));
+            }
+        }
+        diagnostics
+    }
+
+    fn check_tree(&self, keymap: &KeyMap) -> Vec<Diagnostic> {
+        let mut diagnostics = transparent_outputs(keymap);
+        for (location, key) in keymap.located_keys() {
+            let (KeyDef::Tap(tap) | KeyDef::TapHold(tap, _)) = key;
+            if let Some(key @ (Key::MouseWheelUp | Key::MouseWheelDown)) = tap.key() {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: {key:?} isn't supported by ZMK, so it does nothing"
+                )));
+            }
+            match key {
+                KeyDef::Tap(TapKey::Modified(_, key)) if !is_kp(key) => {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "{location}: {key:?} can't be modified in ZMK, so it does nothing"
+                    )));
+                }
+                KeyDef::TapHold(TapKey::Key(key), _) if !is_kp(key) => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "{location}: {key:?} can't be the tap of a hold-tap in ZMK"
+                    )));
+                }
+                KeyDef::TapHold(
+                    tap @ (TapKey::Modified(..)
+                    | TapKey::OneShotMod(_)
+                    | TapKey::Layer(_)
+                    | TapKey::ToggleLayer(_)
+                    | TapKey::OneShotLayer(_)),
+                    _,
+                ) => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "{location}: The tap of a hold-tap must be a regular key in ZMK: {tap:?}"
+                    )));
+                }
+                _ => {}
+            }
+            let regular = match key {
+                KeyDef::Tap(TapKey::Key(key) | TapKey::Modified(_, key)) => is_kp(key),
+                _ => false,
+            };
+            if let Location::Encoder { .. } = location
+                && !regular
+            {
+                diagnostics.push(Diagnostic::error(format!(
+                    "{location}: Encoder bindings must be regular keys in ZMK: {key:?}"
+                )));
+            }
+        }
+        for (location, key_override) in keymap.located_overrides() {
+            if key_override.mods.len() > 1 {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "{location}: ZMK mod-morphs trigger on any of their modifiers, not only all of them"
+                )));
+            }
+        }
+        // A key gets the first override active on a layer, which is only
+        // chained to the overrides defined on the same layer as it.
+        let defined: Vec<_> = keymap
+            .layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .overrides
+                    .iter()
+                    .map(move |o| (&layer.name, layer.override_location(o), o))
+            })
+            .collect();
+        for layer in &keymap.layers {
+            let active: Vec<_> = defined
+                .iter()
+                .filter(|(_, _, key_override)| key_override.layers.contains(&layer.name))
+                .collect();
+            for (i, (home, location, key_override)) in active.iter().enumerate() {
+                if let Some((first_home, first_location, _)) = active[..i]
+                    .iter()
+                    .find(|(_, _, first)| first.input == key_override.input)
+                    && first_home != home
+                {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "{location}: The {first_location} is for the same key on {} and isn't chained to this one, so ZMK ignores this one there",
+                        layer.name
+                    )));
+                }
+            }
+        }
+        diagnostics
+    }
+}
+
+/// Whether the key is a keycode for `&kp`,
+/// so can be used with modifiers and in hold-taps.
+fn is_kp(key: &Key) -> bool {
+    tk(&TapKey::Key(key.clone())).starts_with("&kp ")
+}
+
+const WEST_MANIFEST: &str = "manifest:
+  remotes:
+    - name: zmkfirmware
+      url-base: https://github.com/zmkfirmware
+  projects:
+    - name: zmk
+      remote: zmkfirmware
+      revision: main
+      import: app/west.yml
+  self:
+    path: config
+";
+
+/// The GitHub Actions build matrix.
+fn build(board: &str, shields: &[String]) -> String {
+    let mut build = String::from("include:\n");
+    if shields.is_empty() {
+        build.push_str(&format!("  - board: {board}\n"));
+    }
+    for shield in shields {
+        build.push_str(&format!("  - board: {board}\n    shield: {shield}\n"));
+    }
+    build
+}
+
+/// Kconfig options for the features the keymap uses.
+fn conf(keymap: &KeyMap) -> String {
+    let features = keymap.features();
+    let mut conf = String::new();
+    if features.rgb {
+        conf.push_str("CONFIG_ZMK_RGB_UNDERGLOW=y\n");
+    }
+    if features.backlight {
+        conf.push_str("CONFIG_ZMK_BACKLIGHT=y\n");
+    }
+    if features.ext_power {
+        conf.push_str("CONFIG_ZMK_EXT_POWER=y\n");
+    }
+    if features.combos {
+        // ZMK allocates combos per key position, across all layers,
+        // and fails to build if these limits are exceeded.
+        let max_combos_per_key = combos_per_key(keymap).into_values().max().unwrap_or(0);
+        let max_keys_per_combo = keymap
+            .combos()
+            .map(|combo| combo.inputs.len())
+            .max()
+            .unwrap_or(0);
+        conf.push_str(&format!(
+            "CONFIG_ZMK_COMBO_MAX_COMBOS_PER_KEY={max_combos_per_key}\n"
+        ));
+        conf.push_str(&format!(
+            "CONFIG_ZMK_COMBO_MAX_KEYS_PER_COMBO={max_keys_per_combo}\n"
+        ));
+    }
+    conf
+}
+
+/// ZMK's default `CONFIG_ZMK_COMBO_MAX_COMBOS_PER_KEY`.
+const MAX_COMBOS_PER_KEY: usize = 5;
+
+/// ZMK's default `CONFIG_ZMK_COMBO_MAX_KEYS_PER_COMBO`.
+const MAX_KEYS_PER_COMBO: usize = 4;
+
+/// The number of combos using each key position.
+fn combos_per_key(keymap: &KeyMap) -> BTreeMap<usize, usize> {
+    let mut combos_per_key = BTreeMap::new();
+    for combo in keymap.combos() {
+        for position in &combo.inputs {
+            *combos_per_key.entry(*position).or_default() += 1;
+        }
+    }
+    combos_per_key
 }
 
 struct ComboDef<'a> {
     name: String,
     positions: String,
     output: &'a KeyDef,
-    layer: &'a str,
-    shifts: &'a [Shifted],
+    layers: String,
+    overrides: Vec<&'a KeyOverride>,
 }
 
-fn combos<'a>(layers: &'a [Layer]) -> impl Iterator<Item = ComboDef<'a>> {
-    layers.iter().enumerate().flat_map(|(i, layer)| {
-        layer
-            .combos
-            .iter()
-            .enumerate()
-            .map(move |(j, combo)| ComboDef {
-                layer: &layer.name,
-                name: format!("combo_{i}_{j}"),
-                output: &combo.output,
-                shifts: &layer.shifts,
-                positions: combo
-                    .inputs
-                    .iter()
-                    .map(|inp| inp.position.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
+fn combos(keymap: &KeyMap) -> impl Iterator<Item = ComboDef<'_>> {
+    let layer_combos = keymap
+        .layers
+        .iter()
+        .enumerate()
+        .flat_map(move |(i, layer)| {
+            layer.combos.iter().enumerate().map(move |(j, combo)| {
+                let overrides = keymap.overrides_on(&layer.name).collect();
+                combo_def(format!("combo_{i}_{j}"), combo, overrides)
             })
-    })
+        });
+
+    // Global combos don't have any key overrides.
+    let global_combos = keymap
+        .combos
+        .iter()
+        .enumerate()
+        .map(|(j, combo)| combo_def(format!("combo_global_{j}"), combo, vec![]));
+
+    layer_combos.chain(global_combos)
+}
+
+fn combo_def<'a>(name: String, combo: &'a Combo, overrides: Vec<&'a KeyOverride>) -> ComboDef<'a> {
+    ComboDef {
+        name,
+        output: &combo.output,
+        overrides,
+        layers: combo.layers.join(" "),
+        positions: combo
+            .inputs
+            .iter()
+            .map(|position| position.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
 }
 
 fn kc(key: &Key) -> &'static str {
@@ -208,6 +508,27 @@ fn kc(key: &Key) -> &'static str {
         Key::Bt1 => "BT_SEL 1",
         Key::BtClear => "BT_CLR",
 
+        // RGB underglow
+        Key::RgbToggle => "RGB_TOG",
+        Key::RgbEffectNext => "RGB_EFF",
+        Key::RgbEffectPrev => "RGB_EFR",
+        Key::RgbHueUp => "RGB_HUI",
+        Key::RgbHueDown => "RGB_HUD",
+        Key::RgbSaturationUp => "RGB_SAI",
+        Key::RgbSaturationDown => "RGB_SAD",
+        Key::RgbBrightnessUp => "RGB_BRI",
+        Key::RgbBrightnessDown => "RGB_BRD",
+
+        // Backlight
+        Key::BacklightToggle => "BL_TOG",
+        Key::BacklightUp => "BL_INC",
+        Key::BacklightDown => "BL_DEC",
+
+        // External power
+        Key::ExtPowerToggle => "EP_TOG",
+        Key::ExtPowerOn => "EP_ON",
+        Key::ExtPowerOff => "EP_OFF",
+
         // No appropriate keycode.
         // NOTE: This may indicate that the intermediate
         // representation should not treat these as normal keys?
@@ -247,8 +568,24 @@ fn tk(key: &TapKey) -> String {
             Key::CapsWord => "&caps_word".to_string(),
             Key::Bootloader => "&bootloader".to_string(),
             Key::Unassigned => "&trans".to_string(),
-            Key::NotAllowed => "&none".to_string(),
+            // Not supported, so they do nothing.
+            Key::NotAllowed | Key::MouseWheelUp | Key::MouseWheelDown => "&none".to_string(),
             Key::Bt0 | Key::Bt1 | Key::BtClear => format!("&bt {}", kc(key)),
+            Key::RgbToggle
+            | Key::RgbEffectNext
+            | Key::RgbEffectPrev
+            | Key::RgbHueUp
+            | Key::RgbHueDown
+            | Key::RgbSaturationUp
+            | Key::RgbSaturationDown
+            | Key::RgbBrightnessUp
+            | Key::RgbBrightnessDown => format!("&rgb_ug {}", kc(key)),
+            Key::BacklightToggle | Key::BacklightUp | Key::BacklightDown => {
+                format!("&bl {}", kc(key))
+            }
+            Key::ExtPowerToggle | Key::ExtPowerOn | Key::ExtPowerOff => {
+                format!("&ext_power {}", kc(key))
+            }
             _ => format!("&kp {}", kc(key)),
         },
         TapKey::Layer(layer) => format!("&mo {layer}"),
@@ -260,6 +597,8 @@ fn tk(key: &TapKey) -> String {
         TapKey::OneShotLayer(layer) => {
             format!("&sl {layer}")
         }
+        // Only `&kp` keycodes can be modified.
+        TapKey::Modified(_, key) if !is_kp(key) => "&none".to_string(),
         TapKey::Modified(modifiers, key) => {
             let mut s = String::from("&kp ");
             let mut depth = 0;
@@ -278,10 +617,11 @@ fn tk(key: &TapKey) -> String {
 }
 
 fn hk(hold: &HoldKey, tap: &TapKey) -> String {
-    let key = match tap {
-        TapKey::Key(key) => kc(key),
-        _ => panic!("The tap key in a hold-tap definition must be a regular key: {tap:?}"),
+    // Other tap keys are errors found by `check`.
+    let TapKey::Key(key) = tap else {
+        return "&none".to_string();
     };
+    let key = kc(key);
     match hold {
         HoldKey::Layer(layer) => format!("&lt {layer} {key}"),
         HoldKey::Modifier(modifier) => {
@@ -298,11 +638,90 @@ fn kd(keydef: &KeyDef) -> String {
     }
 }
 
-/// A key that may have a shift override.
-fn skd(key: &KeyDef, shifts: &[Shifted]) -> String {
-    if let Some(Shifted { name, .. }) = shifts.iter().find(|s| &s.input == key) {
-        format!("&{name}")
-    } else {
-        kd(key)
+/// An encoder's binding on a layer, see [`Layer::encoders`](crate::keymap::Layer::encoders).
+fn sensor(encoder: Option<&Encoder>) -> String {
+    let Some(Encoder { ccw, cw }) = encoder else {
+        return "&trans".to_string();
+    };
+    let param = |keydef: &KeyDef| match keydef {
+        KeyDef::Tap(key @ (TapKey::Key(_) | TapKey::Modified(..))) => {
+            tk(key).strip_prefix("&kp ").map(str::to_string)
+        }
+        _ => None,
+    };
+    // Other bindings are errors found by `check`.
+    match (param(cw), param(ccw)) {
+        (Some(cw), Some(ccw)) => format!("&inc_dec_kp {cw} {ccw}"),
+        _ => "&trans".to_string(),
+    }
+}
+
+/// Mod flags matching either side of the modifiers.
+fn mod_flags(mods: &[Modifier]) -> String {
+    let flags: Vec<_> = mods
+        .iter()
+        .map(|modifier| match modifier {
+            Modifier::Alt => "MOD_LALT|MOD_RALT",
+            Modifier::Meta => "MOD_LGUI|MOD_RGUI",
+            Modifier::Ctrl => "MOD_LCTL|MOD_RCTL",
+            Modifier::Shift => "MOD_LSFT|MOD_RSFT",
+        })
+        .collect();
+    format!("({})", flags.join("|"))
+}
+
+/// Each key override with the binding used when it isn't triggered.
+/// If a key has several overrides on a layer they're chained,
+/// so that the first one falls back to the next, and so on.
+fn morphs(keymap: &KeyMap) -> impl Iterator<Item = (&KeyOverride, String)> {
+    keymap.layers.iter().flat_map(|layer| {
+        layer.overrides.iter().enumerate().map(|(i, key_override)| {
+            let binding = match layer.overrides[i + 1..]
+                .iter()
+                .find(|next| next.input == key_override.input)
+            {
+                Some(next) => format!("&{}", next.name),
+                // The hold of a hold-tap key is kept by wrapping the morph,
+                // so it only falls back to the tap.
+                None => match &key_override.input {
+                    KeyDef::TapHold(tap, _) => tk(tap),
+                    input => kd(input),
+                },
+            };
+            (key_override, binding)
+        })
+    })
+}
+
+/// Hold-tap behaviors for key overrides on hold-tap keys,
+/// which tap the override's mod-morph and keep the hold.
+/// The flavor and hold binding match those of `&lt` and `&mt`.
+fn hold_taps(keymap: &KeyMap) -> impl Iterator<Item = (&str, (&'static str, &'static str))> {
+    keymap
+        .overrides()
+        .filter_map(|key_override| match &key_override.input {
+            KeyDef::TapHold(_, hold) => {
+                let behavior = match hold {
+                    HoldKey::Layer(_) => ("tap-preferred", "&mo"),
+                    HoldKey::Modifier(_) => ("hold-preferred", "&kp"),
+                };
+                Some((key_override.name.as_str(), behavior))
+            }
+            KeyDef::Tap(_) => None,
+        })
+}
+
+/// A key that may have a key override.
+fn skd<'a>(key: &KeyDef, mut overrides: impl Iterator<Item = &'a KeyOverride>) -> String {
+    match (overrides.find(|o| &o.input == key), key) {
+        (Some(KeyOverride { name, .. }), KeyDef::TapHold(_, hold)) => {
+            let hold = match hold {
+                HoldKey::Layer(layer) => layer.as_str(),
+                HoldKey::Modifier(modifier) => mc(modifier),
+            };
+            format!("&{name}_ht {hold} 0")
+        }
+        (Some(KeyOverride { name, .. }), KeyDef::Tap(_)) => format!("&{name}"),
+        (None, _) => kd(key),
     }
 }
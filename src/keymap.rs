@@ -1,27 +1,167 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+};
 
-use crate::{keys::*, parse};
+use crate::{
+    diagnostic::Diagnostic,
+    keys::*,
+    parse::{self, ParseError},
+};
 
-#[derive(Default)]
+/// A parsed keyboard layout: its layers, and everything bound on them.
+/// With serde this is the fully resolved keymap, e.g. as JSON.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
 pub struct KeyMap {
+    /// The layers, in order. The first is the base layer.
     pub layers: Vec<Layer>,
-    pub include: String,
+
+    /// Combos that aren't associated with a single layer.
+    #[serde(default)]
+    pub combos: Vec<Combo>,
+
+    /// Raw code to include in the generated files.
+    #[serde(default)]
+    pub includes: Vec<Include>,
+
+    /// Problems found while parsing.
+    #[serde(skip)]
+    pub diagnostics: Vec<Diagnostic>,
 }
 impl KeyMap {
-    pub fn load<P: AsRef<Path>>(path: P) -> Self {
-        parse::parse_keymap(path)
+    /// Load a keyboard layout from a file, or a resolved keymap from a `.json` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
+        parse::load_keymap(path)
     }
 
+    /// Parse a keyboard layout.
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        parse::parse_keymap(s)
+    }
+
+    /// Load a resolved keymap from JSON, as written by the `json` target.
+    pub fn from_json(s: &str) -> Result<Self, ParseError> {
+        serde_json::from_str(s).map_err(ParseError::Json)
+    }
+
+    /// All combos: those defined on each layer, then the global ones.
     pub fn combos(&self) -> impl Iterator<Item = &Combo> {
-        self.layers.iter().flat_map(|layer| layer.combos.iter())
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.combos.iter())
+            .chain(self.combos.iter())
     }
 
-    pub fn shifts(&self) -> impl Iterator<Item = &Shifted> {
-        self.layers.iter().flat_map(|layer| layer.shifts.iter())
+    /// All key overrides, by the layer they're defined in.
+    pub fn overrides(&self) -> impl Iterator<Item = &KeyOverride> {
+        self.layers.iter().flat_map(|layer| layer.overrides.iter())
+    }
+
+    /// All combos, with where they're defined.
+    pub fn located_combos(&self) -> impl Iterator<Item = (Location<'_>, &Combo)> {
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .combos
+                    .iter()
+                    .map(|combo| (Some(layer.name.as_str()), combo))
+            })
+            .chain(self.combos.iter().map(|combo| (None, combo)))
+            .map(|(layer, combo)| {
+                let inputs = combo.inputs.as_slice();
+                (Location::Combo { layer, inputs }, combo)
+            })
+    }
+
+    /// All key overrides, with where they're defined.
+    pub fn located_overrides(&self) -> impl Iterator<Item = (Location<'_>, &KeyOverride)> {
+        self.layers.iter().flat_map(|layer| {
+            layer
+                .overrides
+                .iter()
+                .map(|key_override| (layer.override_location(key_override), key_override))
+        })
+    }
+
+    /// Key overrides that apply on the named layer,
+    /// including ones defined on other layers.
+    pub fn overrides_on<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a KeyOverride> {
+        self.overrides()
+            .filter(move |key_override| key_override.layers.iter().any(|layer| layer == name))
+    }
+
+    /// Raw code to place in the given section of the target's output.
+    /// Includes without an explicit section go in the target's `default` section.
+    pub fn include(&self, target: &str, section: Section, default: Section) -> String {
+        self.includes
+            .iter()
+            .filter(|include| include.target.as_deref().is_none_or(|t| t == target))
+            .filter(|include| include.section.unwrap_or(default) == section)
+            .map(|include| include.code.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The number of encoders bound on any layer.
+    pub fn encoder_count(&self) -> usize {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.encoders.keys())
+            .map(|i| i + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The optional firmware features this keymap uses.
+    pub fn features(&self) -> Features {
+        let mut features = Features {
+            combos: self.combos().next().is_some(),
+            key_overrides: self.overrides().next().is_some(),
+            encoders: self.encoder_count() > 0,
+            ..Default::default()
+        };
+        for key in self.keys() {
+            let tap = match key {
+                KeyDef::Tap(tap) => tap,
+                KeyDef::TapHold(tap, _) => {
+                    features.tap_hold = true;
+                    tap
+                }
+            };
+            match tap.key() {
+                Some(Key::CapsWord) => features.caps_word = true,
+                Some(Key::MouseWheelUp | Key::MouseWheelDown) => features.mouse_keys = true,
+                Some(Key::Bt0 | Key::Bt1 | Key::BtClear) => features.bluetooth = true,
+                Some(
+                    Key::RgbToggle
+                    | Key::RgbEffectNext
+                    | Key::RgbEffectPrev
+                    | Key::RgbHueUp
+                    | Key::RgbHueDown
+                    | Key::RgbSaturationUp
+                    | Key::RgbSaturationDown
+                    | Key::RgbBrightnessUp
+                    | Key::RgbBrightnessDown,
+                ) => features.rgb = true,
+                Some(Key::BacklightToggle | Key::BacklightUp | Key::BacklightDown) => {
+                    features.backlight = true
+                }
+                Some(Key::ExtPowerToggle | Key::ExtPowerOn | Key::ExtPowerOff) => {
+                    features.ext_power = true
+                }
+                _ => {}
+            }
+        }
+        features
     }
 
-    pub fn validate(&self) -> Vec<String> {
-        let mut errors: Vec<String> = vec![];
+    /// Problems with the keymap that don't depend on the target,
+    /// including warnings found while parsing.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics: Vec<Diagnostic> = self.diagnostics.clone();
         let layers: Vec<_> = self.layers.iter().map(|layer| &layer.name).collect();
 
         for key in self.keys() {
@@ -29,10 +169,10 @@ impl KeyMap {
                 KeyDef::Tap(tap_key) => match tap_key {
                     TapKey::Layer(layer)
                     | TapKey::ToggleLayer(layer)
-                    | TapKey::OneShotLayer(layer) => {
-                        if !layers.contains(&layer) {
-                            errors.push(format!("Missing layer: {layer}"));
-                        }
+                    | TapKey::OneShotLayer(layer)
+                        if !layers.contains(&layer) =>
+                    {
+                        diagnostics.push(Diagnostic::error(format!("Missing layer: {layer}")));
                     }
                     _ => {}
                 },
@@ -40,41 +180,399 @@ impl KeyMap {
                     match tap_key {
                         TapKey::Layer(layer)
                         | TapKey::ToggleLayer(layer)
-                        | TapKey::OneShotLayer(layer) => {
-                            if !layers.contains(&layer) {
-                                errors.push(format!("Missing layer: {layer}"));
-                            }
+                        | TapKey::OneShotLayer(layer)
+                            if !layers.contains(&layer) =>
+                        {
+                            diagnostics.push(Diagnostic::error(format!("Missing layer: {layer}")));
                         }
                         _ => {}
                     }
                     if let HoldKey::Layer(layer) = hold_key
                         && !layers.contains(&layer)
                     {
-                        errors.push(format!("Missing layer: {layer}"));
+                        diagnostics.push(Diagnostic::error(format!("Missing layer: {layer}")));
                     }
                 }
             }
         }
-        errors
+        let combo_layers = self.combos().flat_map(|combo| combo.layers.iter());
+        let override_layers = self
+            .overrides()
+            .flat_map(|key_override| key_override.layers.iter());
+        for layer in combo_layers.chain(override_layers) {
+            if !layers.contains(&layer) {
+                diagnostics.push(Diagnostic::error(format!("Missing layer: {layer}")));
+            }
+        }
+        // Keymaps loaded from JSON haven't been checked against their layout.
+        let size = self.layers.first().map_or(0, |layer| layer.layout.len());
+        if self.layers.is_empty() {
+            diagnostics.push(Diagnostic::error("The keymap has no layers"));
+        }
+        for layer in self.layers.iter().skip(1) {
+            if layer.layout.len() != size {
+                diagnostics.push(Diagnostic::error(format!(
+                    "Layer {}: The layer has {} keys but the base layer has {size}",
+                    layer.name,
+                    layer.layout.len()
+                )));
+            }
+        }
+        for (location, combo) in self.located_combos() {
+            if combo.layers.is_empty() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "{location}: The combo's layers are empty, so it's never active"
+                )));
+            }
+        }
+        for (location, key_override) in self.located_overrides() {
+            if key_override.layers.is_empty() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "{location}: The override's layers are empty, so it's never active"
+                )));
+            }
+        }
+        for combo in self.combos() {
+            if let Some(position) = combo.inputs.iter().find(|position| **position >= size) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "Combo position {position} is outside the layout"
+                )));
+            }
+        }
+        if !diagnostics.iter().any(Diagnostic::is_error) {
+            diagnostics.extend(self.check_layers());
+            diagnostics.extend(self.check_combos());
+        }
+        diagnostics
+    }
+
+    /// Problems with combos that are active on the same layer.
+    fn check_combos(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        for layer in &self.layers {
+            let name = &layer.name;
+            let glyphs = |inputs: &[usize]| {
+                let glyphs: Vec<_> = inputs
+                    .iter()
+                    .map(|position| match layer.glyphs.get(*position) {
+                        Some(glyph) => glyph.to_string(),
+                        None => format!("Pos({position})"),
+                    })
+                    .collect();
+                format!("[{}]", glyphs.join(", "))
+            };
+            let combos: Vec<_> = self
+                .combos()
+                .filter(|combo| combo.layers.contains(name))
+                .collect();
+            for (i, combo) in combos.iter().enumerate() {
+                let keys = glyphs(&combo.inputs);
+                for position in &combo.inputs {
+                    if layer.layout.get(*position)
+                        == Some(&KeyDef::Tap(TapKey::Key(Key::Unassigned)))
+                    {
+                        diagnostics.push(Diagnostic::warning(format!(
+                            "Layer {name}: The combo {keys} uses a transparent key at Pos({position})"
+                        )));
+                    }
+                }
+                for other in &combos[i + 1..] {
+                    let other_keys = glyphs(&other.inputs);
+                    let is_subset =
+                        |a: &Combo, b: &Combo| a.inputs.iter().all(|p| b.inputs.contains(p));
+                    match (is_subset(combo, other), is_subset(other, combo)) {
+                        (true, true) => diagnostics.push(Diagnostic::error(format!(
+                            "Layer {name}: The combos {keys} and {other_keys} use the same keys"
+                        ))),
+                        (true, false) | (false, true) => {
+                            let (subset, superset) = if combo.inputs.len() < other.inputs.len() {
+                                (&keys, &other_keys)
+                            } else {
+                                (&other_keys, &keys)
+                            };
+                            diagnostics.push(Diagnostic::warning(format!(
+                                "Layer {name}: The combo {subset} is a subset of {superset}, so {subset} waits for the combo timeout in case it's {superset}"
+                            )));
+                        }
+                        (false, false) => {}
+                    }
+                }
+            }
+        }
+        diagnostics
+    }
+
+    /// Warnings for layers that can't be reached from the base layer,
+    /// and for layers that can be toggled on but not off again.
+    fn check_layers(&self) -> Vec<Diagnostic> {
+        let mut warnings = vec![];
+        let Some(base) = self.layers.first() else {
+            return warnings;
+        };
+
+        let reachable = self.reachable_from(&base.name);
+        for layer in &self.layers {
+            if !reachable.contains(layer.name.as_str()) {
+                warnings.push(Diagnostic::warning(format!(
+                    "Layer {} can't be reached from the base layer {}",
+                    layer.name, base.name
+                )));
+            }
+        }
+
+        for toggled in &self.layers {
+            let name = toggled.name.as_str();
+            let toggles = |key: &KeyDef| matches!(key, KeyDef::Tap(TapKey::ToggleLayer(layer)) if layer == name);
+
+            // Layers that toggle this one on, from the base layer.
+            let togglers: Vec<_> = self
+                .layers
+                .iter()
+                .filter(|layer| layer.name != name && reachable.contains(layer.name.as_str()))
+                .filter(|layer| self.keys_on(&layer.name).any(toggles))
+                .collect();
+            if togglers.is_empty() {
+                continue;
+            }
+
+            // The layer can be toggled off from itself or any layer reachable from it,
+            // or by the toggle key itself if it's transparent on this layer.
+            let toggled_off = self
+                .reachable_from(name)
+                .into_iter()
+                .any(|layer| self.keys_on(layer).any(toggles))
+                || togglers.iter().any(|layer| {
+                    layer.layout.iter().enumerate().any(|(position, key)| {
+                        toggles(key)
+                            && toggled.layout.get(position)
+                                == Some(&KeyDef::Tap(TapKey::Key(Key::Unassigned)))
+                    })
+                });
+            if !toggled_off {
+                warnings.push(Diagnostic::warning(format!(
+                    "Layer {name} is toggled on from {} but can't be toggled off",
+                    togglers
+                        .iter()
+                        .map(|layer| layer.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+        warnings
+    }
+
+    /// Layers that can be activated, directly or indirectly,
+    /// while the named layer is active, including itself.
+    fn reachable_from<'a>(&'a self, name: &'a str) -> HashSet<&'a str> {
+        let mut reachable = HashSet::from([name]);
+        let mut queue = vec![name];
+        while let Some(name) = queue.pop() {
+            for key in self.keys_on(name) {
+                let (tap, hold) = match key {
+                    KeyDef::Tap(tap) => (tap, None),
+                    KeyDef::TapHold(tap, hold) => (tap, Some(hold)),
+                };
+                let tap = match tap {
+                    TapKey::Layer(layer)
+                    | TapKey::ToggleLayer(layer)
+                    | TapKey::OneShotLayer(layer) => Some(layer.as_str()),
+                    _ => None,
+                };
+                let hold = match hold {
+                    Some(HoldKey::Layer(layer)) => Some(layer.as_str()),
+                    _ => None,
+                };
+                for layer in tap.into_iter().chain(hold) {
+                    if reachable.insert(layer) {
+                        queue.push(layer);
+                    }
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Keys that can be pressed while the named layer is active.
+    fn keys_on<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a KeyDef> {
+        self.layers
+            .iter()
+            .filter(move |layer| layer.name == name)
+            .flat_map(|layer| {
+                layer.layout.iter().chain(
+                    layer
+                        .encoders
+                        .values()
+                        .flat_map(|encoder| [&encoder.ccw, &encoder.cw]),
+                )
+            })
+            .chain(
+                self.combos()
+                    .filter(move |combo| combo.layers.iter().any(|layer| layer == name))
+                    .map(|combo| &combo.output),
+            )
+            .chain(
+                self.overrides_on(name)
+                    .map(|key_override| &key_override.output),
+            )
+    }
+
+    /// Iterator over all keys defined in this keymap, with where they're bound.
+    pub fn located_keys(&self) -> impl Iterator<Item = (Location<'_>, &KeyDef)> {
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                let name = layer.name.as_str();
+                let layout = layer.layout.iter().enumerate().map(move |(position, key)| {
+                    let (row, col) = layer.coordinates(position);
+                    (
+                        Location::Layout {
+                            layer: name,
+                            row,
+                            col,
+                        },
+                        key,
+                    )
+                });
+                let combos = layer.combos.iter().map(move |combo| {
+                    let layer = Some(name);
+                    let inputs = combo.inputs.as_slice();
+                    (Location::Combo { layer, inputs }, &combo.output)
+                });
+                let overrides = layer.overrides.iter().map(move |key_override| {
+                    (layer.override_location(key_override), &key_override.output)
+                });
+                let encoders = layer.encoders.iter().flat_map(move |(index, encoder)| {
+                    let location = Location::Encoder {
+                        layer: name,
+                        index: *index,
+                    };
+                    [(location, &encoder.ccw), (location, &encoder.cw)]
+                });
+                layout.chain(combos).chain(overrides).chain(encoders)
+            })
+            .chain(self.combos.iter().map(|combo| {
+                let inputs = combo.inputs.as_slice();
+                (
+                    Location::Combo {
+                        layer: None,
+                        inputs,
+                    },
+                    &combo.output,
+                )
+            }))
     }
 
     /// Iterator over all keys defined in this keymap.
     fn keys(&self) -> impl Iterator<Item = &KeyDef> {
-        self.layers.iter().flat_map(|layer| {
-            layer
-                .keys()
-                .chain(layer.shifts.iter().map(|shifted| &shifted.output))
-        })
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .keys()
+                    .chain(
+                        layer
+                            .overrides
+                            .iter()
+                            .map(|key_override| &key_override.output),
+                    )
+                    .chain(
+                        layer
+                            .encoders
+                            .values()
+                            .flat_map(|encoder| [&encoder.ccw, &encoder.cw]),
+                    )
+            })
+            .chain(self.combos.iter().map(|combo| &combo.output))
+    }
+}
+
+impl FromStr for KeyMap {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
     }
 }
 
+/// Optional firmware features used by a keymap,
+/// i.e. ones that need to be enabled in the firmware config.
+#[derive(Debug, Default)]
+pub struct Features {
+    pub tap_hold: bool,
+    pub combos: bool,
+    pub key_overrides: bool,
+    pub encoders: bool,
+    pub caps_word: bool,
+    pub mouse_keys: bool,
+    pub bluetooth: bool,
+    pub rgb: bool,
+    pub backlight: bool,
+    pub ext_power: bool,
+}
+
+/// A layer of the keymap.
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Layer {
     pub name: String,
+
+    /// The key at each position, row by row.
     pub layout: Layout,
+
+    /// Number of keys in each row of the layout.
+    #[serde(default)]
+    pub rows: Vec<usize>,
+
+    /// The glyph of each key in the layout.
+    #[serde(default)]
+    pub glyphs: Vec<char>,
+
+    /// Combos defined in this layer, which may be active on others.
+    #[serde(default)]
     pub combos: Vec<Combo>,
-    pub shifts: Vec<Shifted>,
+
+    /// Key overrides defined in this layer, which may be active on others.
+    #[serde(default)]
+    pub overrides: Vec<KeyOverride>,
+
+    /// Encoder bindings, by encoder index.
+    /// Encoders without bindings on a layer are transparent.
+    #[serde(default)]
+    pub encoders: BTreeMap<usize, Encoder>,
 }
 impl Layer {
+    /// The `(row, column)` of the key at a position.
+    pub fn coordinates(&self, mut position: usize) -> (usize, usize) {
+        for (row, len) in self.rows.iter().enumerate() {
+            if position < *len {
+                return (row, position);
+            }
+            position -= len;
+        }
+        (self.rows.len(), position)
+    }
+
+    /// Where a key override defined in this layer is, for diagnostics.
+    pub fn override_location<'a>(&'a self, key_override: &'a KeyOverride) -> Location<'a> {
+        let position = self
+            .layout
+            .iter()
+            .position(|key| *key == key_override.input);
+        Location::Override {
+            layer: &self.name,
+            name: &key_override.name,
+            glyph: position.and_then(|position| self.glyphs.get(position).copied()),
+            coordinates: position.map(|position| self.coordinates(position)),
+        }
+    }
+
+    /// The position of the key at `(row, column)`.
+    pub fn position(&self, row: usize, col: usize) -> Option<usize> {
+        let len = self.rows.get(row)?;
+        (col < *len).then(|| self.rows[..row].iter().sum::<usize>() + col)
+    }
+
+    /// The layout's keys and the outputs of its combos.
     pub fn keys(&self) -> impl Iterator<Item = &KeyDef> {
         self.layout
             .iter()
@@ -84,22 +582,135 @@ impl Layer {
 
 pub type Layout = Vec<KeyDef>;
 
-/// Represents a particular key and position in a layer.
-pub struct LayerKey {
-    pub key: KeyDef,
+/// Where a key is bound in the keymap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location<'a> {
+    /// A key in a layer's layout.
+    Layout {
+        layer: &'a str,
+        row: usize,
+        col: usize,
+    },
+
+    /// The output of a combo, by its key positions.
+    /// Global combos don't have a layer.
+    Combo {
+        layer: Option<&'a str>,
+        inputs: &'a [usize],
+    },
+
+    /// The output of a key override, by the layer it's defined in
+    /// and where its input is in that layer, if it's there.
+    Override {
+        layer: &'a str,
+        name: &'a str,
+        glyph: Option<char>,
+        coordinates: Option<(usize, usize)>,
+    },
 
-    /// Key's position in the layer.
-    pub position: usize,
+    /// A rotary encoder binding.
+    Encoder { layer: &'a str, index: usize },
+}
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Layout { layer, row, col } => write!(f, "{layer} [{row}, {col}]"),
+            Location::Combo {
+                layer: Some(layer),
+                inputs,
+            } => write!(f, "{layer} combo at {inputs:?}"),
+            Location::Combo {
+                layer: None,
+                inputs,
+            } => write!(f, "global combo at {inputs:?}"),
+            Location::Override {
+                layer,
+                glyph,
+                coordinates: Some((row, col)),
+                ..
+            } => match glyph {
+                Some(glyph) => write!(f, "{layer} override of {glyph:?} at [{row}, {col}]"),
+                None => write!(f, "{layer} override at [{row}, {col}]"),
+            },
+            Location::Override {
+                layer,
+                name,
+                coordinates: None,
+                ..
+            } => write!(f, "{layer} override ({name})"),
+            Location::Encoder { layer, index } => write!(f, "{layer} encoder {index}"),
+        }
+    }
 }
 
+/// Keys that produce an output when pressed together.
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct Combo {
-    pub inputs: Vec<LayerKey>,
+    /// Positions of the combo's keys in the layout.
+    pub inputs: Vec<usize>,
     pub output: KeyDef,
+
+    /// The layers this combo is active on.
+    pub layers: Vec<String>,
 }
 
-/// An override for a `Shift+Key` behavior.
-pub struct Shifted {
+/// An override for a `Mods+Key` behavior.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct KeyOverride {
+    /// A unique name, used to identify it in the generated code.
     pub name: String,
+
+    /// The modifiers that trigger this override.
+    pub mods: Vec<Modifier>,
+
     pub input: KeyDef,
     pub output: KeyDef,
+
+    /// Trigger modifiers that are kept, rather than
+    /// suppressed, when the output is sent.
+    pub keep_mods: Vec<Modifier>,
+
+    /// The layers this override is active on.
+    pub layers: Vec<String>,
+}
+
+/// Bindings for a rotary encoder.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Encoder {
+    /// Counter-clockwise rotation.
+    pub ccw: KeyDef,
+
+    /// Clockwise rotation.
+    pub cw: KeyDef,
+}
+
+/// Raw code to include in the generated file.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct Include {
+    /// The target this is included for, e.g. `qmk` or `zmk`.
+    /// If `None` it's included for every target.
+    pub target: Option<String>,
+
+    /// Where in the generated file this is placed.
+    /// If `None` the target's default placement is used.
+    pub section: Option<Section>,
+
+    pub code: String,
+}
+
+/// Placement of raw code in the generated file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    /// Top of the file, after the standard includes and defines.
+    Header,
+
+    /// Inside the `behaviors` node (ZMK only).
+    Behaviors,
+
+    /// Inside the root node (ZMK only).
+    Root,
+
+    /// End of the file.
+    Footer,
 }
@@ -1,6 +1,9 @@
+use std::fmt::Display;
+
 macro_rules! define_keys {
     { $($str:literal => $variant:ident),* $(,)? } => {
-        #[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
+        /// A key, with the glyph it's written as in the layout.
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
         pub enum Key {
             $(
                 #[serde(alias = $str)]
@@ -121,13 +124,32 @@ define_keys! {
     "◑" => Bt1,
     "◌" => BtClear,
 
+    "✺" => RgbToggle,
+    "⟳" => RgbEffectNext,
+    "⟲" => RgbEffectPrev,
+    "◓" => RgbHueUp,
+    "◒" => RgbHueDown,
+    "◕" => RgbSaturationUp,
+    "◔" => RgbSaturationDown,
+    "✹" => RgbBrightnessUp,
+    "✸" => RgbBrightnessDown,
+
+    "◈" => BacklightToggle,
+    "◭" => BacklightUp,
+    "◮" => BacklightDown,
+
+    "⏻" => ExtPowerToggle,
+    "⏽" => ExtPowerOn,
+    "⭘" => ExtPowerOff,
+
     "↺" => Bootloader,
 
     "∅" => NotAllowed,
     "⬚" => Unassigned,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
+/// A modifier key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub enum Modifier {
     Alt,
     Meta,
@@ -139,22 +161,81 @@ pub enum Modifier {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TapKey {
     Key(Key),
+
+    /// Activate the layer while held, `Enable(LAYER)`.
     Layer(String),
+
+    /// Toggle the layer on or off, `Toggle(LAYER)`.
     ToggleLayer(String),
+
+    /// Apply the modifier to the next key, `OneShot(Shift)`.
     OneShotMod(Modifier),
+
+    /// Activate the layer for the next key, `OneShot(LAYER)`.
     OneShotLayer(String),
+
+    /// A key with modifiers, e.g. `Ctrl(Shift(Z))`.
     Modified(Vec<Modifier>, Key),
 }
+impl TapKey {
+    /// The underlying key, if this is a regular or modified key.
+    pub fn key(&self) -> Option<&Key> {
+        match self {
+            TapKey::Key(key) | TapKey::Modified(_, key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Written as in a layout's config, e.g. `Ctrl(Shift(Z))`.
+impl Display for TapKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapKey::Key(key) => write!(f, "{key:?}"),
+            TapKey::Layer(layer) => write!(f, "Enable({layer})"),
+            TapKey::ToggleLayer(layer) => write!(f, "Toggle({layer})"),
+            TapKey::OneShotMod(modifier) => write!(f, "OneShot({modifier:?})"),
+            TapKey::OneShotLayer(layer) => write!(f, "OneShot({layer})"),
+            TapKey::Modified(modifiers, key) => {
+                for modifier in modifiers {
+                    write!(f, "{modifier:?}(")?;
+                }
+                write!(f, "{key:?}{}", ")".repeat(modifiers.len()))
+            }
+        }
+    }
+}
+impl serde::Serialize for TapKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
 /// A key for a hold action.
 #[derive(Debug, Clone, PartialEq)]
 pub enum HoldKey {
+    /// Activate the layer, `Layer(LAYER)`.
     Layer(String),
+
     Modifier(Modifier),
 }
+/// Written as in a layout's config, e.g. `Layer(NAV)`.
+impl Display for HoldKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HoldKey::Layer(layer) => write!(f, "Layer({layer})"),
+            HoldKey::Modifier(modifier) => write!(f, "{modifier:?}"),
+        }
+    }
+}
+impl serde::Serialize for HoldKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
 /// A complete definition for a key.
-#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(untagged)]
 pub enum KeyDef {
     Tap(TapKey),
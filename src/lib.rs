@@ -0,0 +1,34 @@
+//! Parse a visual keyboard layout and generate keymaps for keyboard firmware.
+//!
+//! ```no_run
+//! use kbl::{Diagnostic, Format, KeyMap, format::ZMK};
+//!
+//! let keymap = KeyMap::load("layout.kbl")?;
+//! let zmk = ZMK::default();
+//! let mut diagnostics = keymap.validate();
+//! // Only check the target once the keymap itself is valid.
+//! if !diagnostics.iter().any(Diagnostic::is_error) {
+//!     diagnostics.extend(zmk.check(&keymap));
+//! }
+//! for diagnostic in &diagnostics {
+//!     eprintln!("{diagnostic}");
+//! }
+//! // A keymap with errors gives incomplete output.
+//! if !diagnostics.iter().any(Diagnostic::is_error) {
+//!     println!("{}", zmk.format(&keymap));
+//! }
+//! # Ok::<(), kbl::ParseError>(())
+//! ```
+//!
+//! Custom targets implement [`Format`].
+
+pub mod diagnostic;
+pub mod format;
+pub mod keymap;
+pub mod keys;
+mod parse;
+
+pub use diagnostic::{Diagnostic, Severity};
+pub use format::Format;
+pub use keymap::KeyMap;
+pub use parse::ParseError;
@@ -1,19 +1,29 @@
-mod format;
-mod keymap;
-mod keys;
-mod parse;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use std::{path::PathBuf, str::FromStr};
+mod manifest;
+mod output;
 
 use bpaf::Bpaf;
-use keymap::KeyMap;
-
-use format::*;
+use kbl::{
+    Diagnostic, KeyMap, ParseError,
+    format::{Format, JSON, KMonad, Keyberon, QMK, QMKJSON, RMK, Vial, ZMK},
+};
+use manifest::{Destination, Manifest, Output};
 
 #[derive(Debug, Clone, Bpaf)]
 enum Target {
     Qmk,
+    QmkJson,
     Zmk,
+    Vial,
+    Rmk,
+    Keyberon,
+    KMonad,
+    Json,
 }
 impl FromStr for Target {
     type Err = String;
@@ -21,16 +31,29 @@ impl FromStr for Target {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "qmk" => Ok(Self::Qmk),
+            "qmk-json" => Ok(Self::QmkJson),
             "zmk" => Ok(Self::Zmk),
+            "vial" => Ok(Self::Vial),
+            "rmk" => Ok(Self::Rmk),
+            "keyberon" => Ok(Self::Keyberon),
+            "kmonad" => Ok(Self::KMonad),
+            "json" => Ok(Self::Json),
             _ => Err(format!("Invalid target: {s}")),
         }
     }
 }
 impl Target {
-    fn format(&self, keymap: &KeyMap) -> String {
+    /// The target's format, with options from the environment.
+    fn format(&self) -> Box<dyn Format> {
         match self {
-            Target::Qmk => QMK::format(keymap),
-            Target::Zmk => ZMK::format(keymap),
+            Target::Qmk => Box::new(QMK::from_env()),
+            Target::QmkJson => Box::new(QMKJSON::from_env()),
+            Target::Zmk => Box::new(ZMK::from_env()),
+            Target::Vial => Box::new(Vial::from_env()),
+            Target::Rmk => Box::new(RMK::from_env()),
+            Target::Keyberon => Box::new(Keyberon::from_env()),
+            Target::KMonad => Box::new(KMonad::from_env()),
+            Target::Json => Box::new(JSON),
         }
     }
 }
@@ -38,26 +61,274 @@ impl Target {
 #[derive(Clone, Debug, Bpaf)]
 #[bpaf(options, version)]
 /// Parse a keyboard layout to a keymap file.
+enum Command {
+    /// Regenerate keymaps whenever the layout changes.
+    #[bpaf(command)]
+    Watch(#[bpaf(external(watch_args))] WatchArgs),
+
+    /// Build every output listed in a `kbl.toml` manifest.
+    #[bpaf(command)]
+    Build(#[bpaf(external(build_args))] BuildArgs),
+
+    Generate(#[bpaf(external(args))] Args),
+}
+
+#[derive(Clone, Debug, Bpaf)]
 struct Args {
-    /// The target, either `qmk` or `zmk`.
+    /// Write a complete config directory for the target
+    /// to this directory, instead of printing the keymap.
+    #[bpaf(argument("DIR"))]
+    tree: Option<PathBuf>,
+
+    /// Write the keymap to this file, instead of printing it.
+    /// The file isn't rewritten if it's unchanged.
+    #[bpaf(short('o'), long("output"), argument("FILE"))]
+    output: Option<PathBuf>,
+
+    /// Treat warnings as errors.
+    deny_warnings: bool,
+
+    /// The target: `qmk`, `qmk-json` for a QMK `keymap.json`, `zmk`,
+    /// `vial` for a Vial `.vil` layout, `rmk` for an RMK `keyboard.toml`,
+    /// `keyberon` for a Rust module, `kmonad` for a KMonad `.kbd`,
+    /// or `json` for the resolved keymap.
     #[bpaf(positional)]
     target: Target,
 
-    /// Keyboard layout to parse.
+    /// Keyboard layout to parse, or `-` to read it from stdin.
+    /// A resolved keymap can be given as JSON instead.
     #[bpaf(positional)]
     path: PathBuf,
 }
 
+#[derive(Clone, Debug, Bpaf)]
+struct WatchArgs {
+    /// Write a QMK keymap to this file.
+    #[bpaf(argument("FILE"))]
+    qmk: Option<PathBuf>,
+
+    /// Write a ZMK keymap to this file.
+    #[bpaf(argument("FILE"))]
+    zmk: Option<PathBuf>,
+
+    /// Keyboard layout to watch.
+    #[bpaf(positional)]
+    path: PathBuf,
+}
+
+#[derive(Clone, Debug, Bpaf)]
+struct BuildArgs {
+    /// Treat warnings as errors.
+    deny_warnings: bool,
+
+    /// The project manifest.
+    #[bpaf(positional("MANIFEST"), fallback("kbl.toml".into()))]
+    manifest: PathBuf,
+}
+
 fn main() -> Result<(), Vec<String>> {
-    let opts = args().run();
-    let keymap = KeyMap::load(&opts.path);
+    match command().run() {
+        Command::Watch(opts) => watch(&opts),
+        Command::Build(opts) => build(&opts),
+        Command::Generate(opts) => generate(&opts),
+    }
+}
+
+fn generate(opts: &Args) -> Result<(), Vec<String>> {
+    if opts.tree.is_some() && opts.output.is_some() {
+        return Err(vec![
+            "--tree and --output can't be used together".to_string(),
+        ]);
+    }
+    let keymap = if opts.path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())
+            .map_err(ParseError::from)
+            .and_then(|layout| {
+                if layout.trim_start().starts_with('{') {
+                    KeyMap::from_json(&layout)
+                } else {
+                    layout.parse::<KeyMap>()
+                }
+            })
+    } else {
+        KeyMap::load(&opts.path)
+    }
+    .map_err(|err| vec![err.to_string()])?;
+    let format = opts.target.format();
+
+    let (errors, warnings): (Vec<_>, Vec<_>) =
+        diagnose(&keymap, format.as_ref(), opts.tree.is_some())
+            .into_iter()
+            .partition(|diagnostic| diagnostic.is_error() || opts.deny_warnings);
+    for warning in warnings {
+        eprintln!("{warning}");
+    }
+    if errors.is_empty() {
+        if let Some(dir) = &opts.tree {
+            let files = format
+                .tree(&keymap)
+                .ok_or_else(|| vec![format!("The {:?} target can't write a tree", opts.target)])?;
+            write_tree(dir, files).map_err(|err| vec![err.to_string()])?;
+        } else if let Some(path) = &opts.output {
+            let output = format.format(&keymap);
+            output::write(path, &format!("{output}\n")).map_err(|err| vec![err.to_string()])?;
+        } else {
+            let output = format.format(&keymap);
+            println!("{output}");
+        }
+        Ok(())
+    } else {
+        Err(errors.iter().map(ToString::to_string).collect())
+    }
+}
 
-    let errors = keymap.validate();
+/// Parse the manifest's layout once and write each of its outputs.
+/// Outputs with errors are skipped, but the others are still written.
+fn build(opts: &BuildArgs) -> Result<(), Vec<String>> {
+    let manifest = Manifest::load(&opts.manifest).map_err(|err| vec![err])?;
+    let keymap = KeyMap::load(&manifest.layout).map_err(|err| vec![err.to_string()])?;
+
+    let mut errors = vec![];
+    for diagnostic in keymap.validate() {
+        if diagnostic.is_error() || opts.deny_warnings {
+            errors.push(diagnostic.to_string());
+        } else {
+            eprintln!("{diagnostic}");
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    for Output {
+        destination,
+        target,
+    } in &manifest.outputs
+    {
+        let format = target.format();
+        // Every output has a destination once the manifest is loaded.
+        let Some(destination) = destination else {
+            continue;
+        };
+        let path = destination.path();
+
+        let diagnostics = match destination {
+            Destination::Path(_) => format.check(&keymap),
+            Destination::Tree(_) => format.check_tree(&keymap),
+        };
+        let mut failed = false;
+        for diagnostic in diagnostics {
+            let message = format!("{}: {diagnostic}", path.display());
+            if diagnostic.is_error() || opts.deny_warnings {
+                errors.push(message);
+                failed = true;
+            } else {
+                eprintln!("{message}");
+            }
+        }
+        if failed {
+            continue;
+        }
+
+        let written = match destination {
+            Destination::Path(path) => {
+                let output = format.format(&keymap);
+                output::write(path, &format!("{output}\n")).map_err(|err| err.to_string())
+            }
+            Destination::Tree(dir) => match format.tree(&keymap) {
+                Some(files) => write_tree(dir, files).map_err(|err| err.to_string()),
+                None => Err(format!("{}: The target can't write a tree", dir.display())),
+            },
+        };
+        match written {
+            Ok(()) => eprintln!("Wrote {}", path.display()),
+            Err(err) => errors.push(err),
+        }
+    }
     if errors.is_empty() {
-        let output = opts.target.format(&keymap);
-        println!("{output}");
         Ok(())
     } else {
         Err(errors)
     }
 }
+
+/// Poll the layout for changes and regenerate the keymaps when it does.
+/// Problems are reported without exiting, so the layout can be fixed.
+fn watch(opts: &WatchArgs) -> Result<(), Vec<String>> {
+    let outputs: Vec<_> = [(Target::Qmk, &opts.qmk), (Target::Zmk, &opts.zmk)]
+        .into_iter()
+        .filter_map(|(target, path)| Some((target, path.as_ref()?)))
+        .collect();
+    if outputs.is_empty() {
+        return Err(vec![
+            "Nothing to write, pass --qmk and/or --zmk".to_string(),
+        ]);
+    }
+
+    let mut modified = None;
+    loop {
+        // The file may briefly not exist while an editor saves it.
+        let mtime = fs_err::metadata(&opts.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if mtime.is_some() && mtime != modified {
+            modified = mtime;
+            regenerate(&opts.path, &outputs);
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn regenerate(path: &Path, outputs: &[(Target, &PathBuf)]) {
+    let keymap = match KeyMap::load(path) {
+        Ok(keymap) => keymap,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return;
+        }
+    };
+    let diagnostics = keymap.validate();
+    for diagnostic in &diagnostics {
+        eprintln!("{diagnostic}");
+    }
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        return;
+    }
+
+    for (target, output) in outputs {
+        let format = target.format();
+        let diagnostics = format.check(&keymap);
+        for diagnostic in &diagnostics {
+            eprintln!("{}: {diagnostic}", output.display());
+        }
+        if diagnostics.iter().any(Diagnostic::is_error) {
+            continue;
+        }
+        let keymap = format.format(&keymap);
+        match output::write(output, &format!("{keymap}\n")) {
+            Ok(()) => eprintln!("Wrote {}", output.display()),
+            Err(err) => eprintln!("error: {err}"),
+        }
+    }
+}
+
+/// Problems with the keymap, both in general and for the target.
+/// The target is only checked once the keymap is valid.
+fn diagnose(keymap: &KeyMap, format: &dyn Format, tree: bool) -> Vec<Diagnostic> {
+    let mut diagnostics = keymap.validate();
+    if !diagnostics.iter().any(Diagnostic::is_error) {
+        if tree {
+            diagnostics.extend(format.check_tree(keymap));
+        } else {
+            diagnostics.extend(format.check(keymap));
+        }
+    }
+    diagnostics
+}
+
+fn write_tree(dir: &Path, files: Vec<(PathBuf, String)>) -> std::io::Result<()> {
+    for (path, contents) in files {
+        output::write(&dir.join(path), &contents)?;
+    }
+    Ok(())
+}
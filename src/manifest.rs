@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+
+use kbl::format::{Format, JSON, KMonad, Keyberon, QMK, QMKJSON, RMK, Vial, ZMK};
+
+/// A `kbl.toml` project manifest, listing the outputs to build from a layout.
+#[derive(Debug, serde::Deserialize)]
+pub struct Manifest {
+    /// The keyboard layout.
+    pub layout: PathBuf,
+
+    #[serde(rename = "output")]
+    pub outputs: Vec<Output>,
+}
+impl Manifest {
+    /// Load a manifest, with its paths resolved relative to it.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let manifest = fs_err::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut manifest: Manifest =
+            toml::from_str(&manifest).map_err(|err| format!("{}: {err}", path.display()))?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        manifest.layout = dir.join(&manifest.layout);
+        for output in &mut manifest.outputs {
+            output.destination = match output.destination.take() {
+                Some(Destination::Path(path)) => Some(Destination::Path(dir.join(path))),
+                Some(Destination::Tree(tree)) => Some(Destination::Tree(dir.join(tree))),
+                None => {
+                    return Err(format!(
+                        "{}: Each output needs either a `path` or a `tree`",
+                        path.display()
+                    ));
+                }
+            };
+        }
+        Ok(manifest)
+    }
+}
+
+/// An output to build, with the target's options.
+#[derive(Debug, serde::Deserialize)]
+pub struct Output {
+    #[serde(flatten)]
+    pub destination: Option<Destination>,
+
+    #[serde(flatten)]
+    pub target: OutputTarget,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Destination {
+    /// Write the keymap to this file.
+    Path(PathBuf),
+
+    /// Write a complete config directory for the target to this directory.
+    Tree(PathBuf),
+}
+impl Destination {
+    pub fn path(&self) -> &Path {
+        match self {
+            Destination::Path(path) | Destination::Tree(path) => path,
+        }
+    }
+}
+
+/// The target, e.g. `target = "qmk"`, and its options.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum OutputTarget {
+    Qmk(QMK),
+    #[serde(rename = "qmk-json")]
+    QmkJson(QMKJSON),
+    Zmk(ZMK),
+    Vial(Vial),
+    Rmk(RMK),
+    Keyberon(Keyberon),
+    KMonad(KMonad),
+    Json(JSON),
+}
+impl OutputTarget {
+    pub fn format(&self) -> &dyn Format {
+        match self {
+            OutputTarget::Qmk(qmk) => qmk,
+            OutputTarget::QmkJson(qmk_json) => qmk_json,
+            OutputTarget::Zmk(zmk) => zmk,
+            OutputTarget::Vial(vial) => vial,
+            OutputTarget::Rmk(rmk) => rmk,
+            OutputTarget::Keyberon(keyberon) => keyberon,
+            OutputTarget::KMonad(kmonad) => kmonad,
+            OutputTarget::Json(json) => json,
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::path::Path;
+
+/// Write a file atomically, by writing a temporary file next to it
+/// and renaming it. If the file already has these contents it's left
+/// untouched, so firmware builds don't see it as changed.
+pub fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    if fs_err::read_to_string(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if !dir.as_os_str().is_empty() {
+        fs_err::create_dir_all(dir)?;
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{name}.tmp"));
+    fs_err::write(&tmp, contents)?;
+    fs_err::rename(&tmp, path)
+}
@@ -1,7 +1,15 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+};
+
+use indexmap::IndexMap;
 
 use crate::{
-    keymap::{Combo, KeyMap, Layer, LayerKey, Shifted},
+    diagnostic::Diagnostic,
+    keymap::{Combo, Encoder, Include, KeyMap, KeyOverride, Layer, Section},
     keys::*,
 };
 
@@ -9,31 +17,179 @@ use crate::{
 struct LayerConfig {
     /// Define optional hold actions for keys.
     #[serde(default)]
-    holds: HashMap<char, HoldKey>,
+    holds: IndexMap<char, HoldKey>,
 
     /// If a key is more complex than a "normal"
     /// key, e.g. mods plus a key, define it here.
     #[serde(default)]
-    defs: HashMap<char, TapKey>,
+    defs: IndexMap<char, TapKey>,
 
     /// Define combos associated with this layer.
     #[serde(default)]
-    combos: HashMap<Vec<char>, KeyDef>,
+    combos: IndexMap<Vec<ComboKey>, ComboConfig>,
 
     /// Shift overrides associated with this layer.
     #[serde(default)]
-    shifts: HashMap<char, KeyDef>,
+    shifts: IndexMap<char, OverrideConfig>,
+
+    /// Key overrides associated with this layer, by their trigger mods.
+    #[serde(default)]
+    overrides: IndexMap<Mods, IndexMap<char, OverrideConfig>>,
+
+    /// Encoder bindings for this layer, by encoder index.
+    #[serde(default)]
+    encoders: BTreeMap<usize, Encoder>,
+}
+
+/// Config that applies across all layers.
+#[derive(Debug, Default, serde::Deserialize)]
+struct GlobalConfig {
+    /// Define combos that aren't associated with a single layer.
+    #[serde(default)]
+    combos: IndexMap<Vec<ComboKey>, ComboConfig>,
+}
+
+/// A combo's output, optionally with the layers it's active on.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum ComboConfig {
+    Output(KeyDef),
+    Options { output: KeyDef, layers: Vec<String> },
+}
+
+/// A key override's output, optionally with additional options.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum OverrideConfig {
+    Output(KeyDef),
+    Options {
+        output: KeyDef,
+
+        /// Trigger mods to keep rather than suppress.
+        #[serde(default, rename = "keep-mods")]
+        keep_mods: Vec<Modifier>,
+
+        /// The layers the override is active on,
+        /// if not just the layer it's defined in.
+        layers: Option<Vec<String>>,
+    },
 }
 
-pub fn parse_keymap<P: AsRef<Path>>(path: P) -> KeyMap {
-    let keymap = fs_err::read_to_string(path).expect("Unable to read file");
-    let (keymap, include) = keymap
-        .split_once("\n===\n")
-        .unwrap_or((keymap.as_str(), ""));
-    let layers = keymap.split("\n---\n");
+/// A combination of modifiers, e.g. `Ctrl+Shift`.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Mods(Vec<Modifier>);
+
+/// An input key of a combo, specified by its glyph,
+/// its position (`Pos(12)`), or its row and column (`[1, 3]`).
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ComboKey {
+    Glyph(char),
+    Position(usize),
+    Coordinates(usize, usize),
+}
+
+/// An error in a keyboard layout, which prevents it from being parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The layout file couldn't be read.
+    Io(std::io::Error),
+
+    /// A layer's config, or the global config, isn't valid.
+    Config {
+        /// The layer name, or `None` for the global config.
+        layer: Option<String>,
+        error: serde_yaml::Error,
+    },
+
+    /// A layer doesn't have a layout after its name.
+    MissingLayout { layer: String },
+
+    /// A glyph isn't a known key and doesn't have a def.
+    UnknownGlyph { layer: String, glyph: char },
+
+    /// A combo key doesn't match exactly one key in the layer.
+    Combo { layer: String, message: String },
+
+    /// A resolved keymap isn't valid JSON for a `KeyMap`.
+    Json(serde_json::Error),
+
+    /// A raw code header names an unknown target or section.
+    Include { token: String },
+}
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "{err}"),
+            ParseError::Config {
+                layer: Some(layer),
+                error,
+            } => write!(f, "Layer {layer}: Invalid config: {error}"),
+            ParseError::Config { layer: None, error } => {
+                write!(f, "Invalid global config: {error}")
+            }
+            ParseError::MissingLayout { layer } => {
+                write!(f, "Layer {layer}: Expected a layout after the layer name")
+            }
+            ParseError::UnknownGlyph { layer, glyph } => write!(
+                f,
+                "Layer {layer}: {glyph:?} isn't a known key, so it needs a def"
+            ),
+            ParseError::Combo { layer, message } => write!(f, "Layer {layer}: {message}"),
+            ParseError::Json(err) => write!(f, "Invalid keymap JSON: {err}"),
+            ParseError::Include { token } => write!(
+                f,
+                "Invalid include header: {token:?} isn't a target ({}) or a section (header, behaviors, root, footer)",
+                INCLUDE_TARGETS.join(", ")
+            ),
+        }
+    }
+}
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            ParseError::Config { error, .. } => Some(error),
+            ParseError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+/// Load a layout, or a resolved keymap if it's a `.json` file.
+pub fn load_keymap<P: AsRef<Path>>(path: P) -> Result<KeyMap, ParseError> {
+    let keymap = fs_err::read_to_string(&path)?;
+    if path.as_ref().extension().is_some_and(|ext| ext == "json") {
+        KeyMap::from_json(&keymap)
+    } else {
+        parse_keymap(&keymap)
+    }
+}
+
+pub fn parse_keymap(keymap: &str) -> Result<KeyMap, ParseError> {
+    let (keymap, includes) = match keymap.split_once("\n===") {
+        Some((keymap, includes)) => (keymap, parse_includes(&format!("==={includes}"))?),
+        None => (keymap, vec![]),
+    };
+    let mut layers = keymap.split("\n---\n").peekable();
+
+    // The first section is global config rather
+    // than a layer if it starts with a config key.
+    let global: GlobalConfig = match layers.next_if(|section| {
+        let first_line = section.trim().lines().next().unwrap_or_default();
+        first_line.contains(':')
+    }) {
+        Some(config) => serde_yaml::from_str(config)
+            .map_err(|error| ParseError::Config { layer: None, error })?,
+        None => GlobalConfig::default(),
+    };
 
     let mut keymap = KeyMap {
-        include: include.to_string(),
+        includes,
         ..Default::default()
     };
 
@@ -45,33 +201,43 @@ pub fn parse_keymap<P: AsRef<Path>>(path: P) -> KeyMap {
         let parts: Vec<&str> = layer.trim().splitn(3, "\n\n").collect();
 
         let name = parts[0];
-        let layout = parts[1];
-        let mut config: LayerConfig = parts
-            .get(2)
-            .map(|config| {
-                serde_yaml::from_str(config)
-                    .inspect_err(|err| {
-                        eprintln!("Err: {err:?}");
-                        eprintln!("{config}");
-                    })
-                    .unwrap()
-            })
-            .unwrap_or_default();
+        let layout = parts.get(1).ok_or_else(|| ParseError::MissingLayout {
+            layer: name.to_string(),
+        })?;
+        let mut config: LayerConfig = match parts.get(2) {
+            Some(config) => serde_yaml::from_str(config).map_err(|error| ParseError::Config {
+                layer: Some(name.to_string()),
+                error,
+            })?,
+            None => LayerConfig::default(),
+        };
+        let unknown_glyph = |glyph| ParseError::UnknownGlyph {
+            layer: name.to_string(),
+            glyph,
+        };
+
+        // Number of keys in each row of the layout.
+        let rows = layout
+            .lines()
+            .map(|row| row.chars().filter(|ch| !ch.is_whitespace()).count())
+            .filter(|count| *count > 0)
+            .collect();
 
         // Figure out mapping of chars to the key definitions.
+        let glyphs: Vec<char> = layout.chars().filter(|ch| !ch.is_whitespace()).collect();
         let mut layer_def = vec![];
         let mut keys: HashMap<char, KeyDef> = HashMap::default();
-        for ch in layout.chars().filter(|ch| !ch.is_whitespace()) {
+        for &ch in &glyphs {
             // If this char is mapped to a specific definition, use that.
             // Otherwise use the default definition.
             let tap = if let Some(def) = config.defs.get(&ch) {
                 def.clone()
             } else {
-                TapKey::try_from(ch).unwrap()
+                TapKey::try_from(ch).map_err(|_| unknown_glyph(ch))?
             };
 
             // Include a hold action, if one's specified.
-            let key = match config.holds.remove(&ch) {
+            let key = match config.holds.shift_remove(&ch) {
                 Some(hold) => KeyDef::TapHold(tap, hold),
                 None => KeyDef::Tap(tap),
             };
@@ -80,55 +246,276 @@ pub fn parse_keymap<P: AsRef<Path>>(path: P) -> KeyMap {
             layer_def.push(key);
         }
 
-        let combos = config
+        // Defs and holds for glyphs that aren't in the layout are ignored,
+        // which usually means a typo or a key that was moved.
+        let mut unused: Vec<_> = config
+            .defs
+            .keys()
+            .filter(|ch| !glyphs.contains(ch))
+            .map(|ch| ("def", ch))
+            .chain(config.holds.keys().map(|ch| ("hold", ch)))
+            .collect();
+        unused.sort();
+        for (kind, ch) in unused {
+            keymap.diagnostics.push(Diagnostic::warning(format!(
+                "Layer {name}: The {kind} for {ch:?} is unused, because it isn't in the layer"
+            )));
+        }
+
+        let mut layer = Layer {
+            name: name.to_string(),
+            layout: layer_def,
+            rows,
+            glyphs,
+            combos: vec![],
+            overrides: vec![],
+            encoders: config.encoders,
+        };
+
+        layer.combos = config
             .combos
             .into_iter()
-            .map(|(inps, output)| {
-                let inputs: Vec<_> = inps
-                .into_iter()
-                .map(|ch| {
-                    if let Some(key) = keys.get(&ch) {
-                        let position = layer_def.iter().position(|k| k == key).unwrap();
-                        LayerKey {
-                            key: key.clone(),
-                            position,
-                        }
-                    } else {
-                        panic!(
-                            "Layer {name}: Expected {:?} for a combo, but it isn't in the layer.",
-                            ch
-                        );
-                    }
-                })
-                .collect();
-                Combo { inputs, output }
-            })
-            .collect();
-        let shifts: Vec<_> = config
+            .map(|(inputs, combo)| parse_combo(&layer, inputs, combo, &[&layer.name]))
+            .collect::<Result<_, ParseError>>()?;
+        // Shifts are shorthand for overrides triggered by Shift.
+        let shifts = config
             .shifts
             .into_iter()
+            .map(|(input, config)| (vec![Modifier::Shift], input, config));
+        let overrides = config
+            .overrides
+            .into_iter()
+            .flat_map(|(Mods(mods), overrides)| {
+                overrides
+                    .into_iter()
+                    .map(move |(input, config)| (mods.clone(), input, config))
+            });
+        layer.overrides = shifts
+            .chain(overrides)
             .enumerate()
-            .map(|(i, (input, output))| {
-                let input = keys
-                    .get(&input)
-                    .cloned()
-                    .unwrap_or_else(|| KeyDef::Tap(TapKey::try_from(input).unwrap()));
-                let name = format!("shift_{name}_{i}");
-                Shifted {
-                    name,
+            .map(|(i, (mods, input, config))| {
+                let (output, keep_mods, layers) = match config {
+                    OverrideConfig::Output(output) => (output, vec![], None),
+                    OverrideConfig::Options {
+                        output,
+                        keep_mods,
+                        layers,
+                    } => (output, keep_mods, layers),
+                };
+                let input = match keys.get(&input) {
+                    Some(key) => key.clone(),
+                    None => {
+                        // Only expected if the override is for other layers.
+                        if layers.is_none() {
+                            keymap.diagnostics.push(Diagnostic::warning(format!(
+                                "Layer {name}: The override for {input:?} is unused, because it isn't in the layer"
+                            )));
+                        }
+                        KeyDef::Tap(TapKey::try_from(input).map_err(|_| unknown_glyph(input))?)
+                    }
+                };
+                Ok(KeyOverride {
+                    name: format!("override_{name}_{i}"),
+                    mods,
                     input,
                     output,
-                }
+                    keep_mods,
+                    layers: layers.unwrap_or_else(|| vec![name.to_string()]),
+                })
             })
+            .collect::<Result<_, ParseError>>()?;
+        keymap.layers.push(layer);
+    }
+
+    // Global combos are resolved against the base layer
+    // and by default are active on all layers.
+    if let Some(base) = keymap.layers.first() {
+        let all_layers: Vec<&str> = keymap
+            .layers
+            .iter()
+            .map(|layer| layer.name.as_str())
             .collect();
-        keymap.layers.push(Layer {
-            name: name.to_string(),
-            layout: layer_def,
-            combos,
-            shifts,
-        });
+        keymap.combos = global
+            .combos
+            .into_iter()
+            .map(|(inputs, combo)| parse_combo(base, inputs, combo, &all_layers))
+            .collect::<Result<_, ParseError>>()?;
+    }
+    Ok(keymap)
+}
+
+/// Resolve a combo's inputs to positions in the layer.
+fn parse_combo(
+    layer: &Layer,
+    inputs: Vec<ComboKey>,
+    combo: ComboConfig,
+    default_layers: &[&str],
+) -> Result<Combo, ParseError> {
+    let err = |message: String| ParseError::Combo {
+        layer: layer.name.clone(),
+        message,
+    };
+    let inputs = inputs
+        .into_iter()
+        .map(|input| {
+            let position = match input {
+                ComboKey::Glyph(ch) => {
+                    let mut positions = layer
+                        .glyphs
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, glyph)| **glyph == ch)
+                        .map(|(i, _)| i);
+                    match (positions.next(), positions.next()) {
+                        (Some(position), None) => position,
+                        (Some(_), Some(_)) => return Err(err(format!(
+                            "{ch:?} is in the layer more than once, so it's ambiguous in a combo. Use its position instead, e.g. [row, column]."
+                        ))),
+                        (None, _) => return Err(err(format!(
+                            "Expected {ch:?} for a combo, but it isn't in the layer."
+                        ))),
+                    }
+                }
+                ComboKey::Position(position) => position,
+                ComboKey::Coordinates(row, col) => {
+                    layer.position(row, col).ok_or_else(|| err(format!(
+                        "Expected a key at [{row}, {col}] for a combo, but there isn't one."
+                    )))?
+                }
+            };
+            if position >= layer.layout.len() {
+                return Err(err(format!(
+                    "Expected a key at Pos({position}) for a combo, but there isn't one."
+                )));
+            }
+            Ok(position)
+        })
+        .collect::<Result<_, _>>()?;
+
+    let (output, layers) = match combo {
+        ComboConfig::Output(output) => (output, None),
+        ComboConfig::Options { output, layers } => (output, Some(layers)),
+    };
+    Ok(Combo {
+        inputs,
+        output,
+        layers: layers.unwrap_or_else(|| {
+            default_layers
+                .iter()
+                .map(|layer| layer.to_string())
+                .collect()
+        }),
+    })
+}
+
+/// The targets that place raw code in their output.
+const INCLUDE_TARGETS: [&str; 2] = ["qmk", "zmk"];
+
+/// Parse the raw code sections, each starting with a header line
+/// of the form `=== [target] [section]`, e.g. `=== zmk root`.
+fn parse_includes(s: &str) -> Result<Vec<Include>, ParseError> {
+    let mut includes: Vec<Include> = vec![];
+    for line in s.lines() {
+        if let Some(header) = line.strip_prefix("===") {
+            let mut include = Include::default();
+            for token in header.split_whitespace() {
+                match Section::from_str(token) {
+                    Ok(section) => include.section = Some(section),
+                    Err(_) if INCLUDE_TARGETS.contains(&token) => {
+                        include.target = Some(token.to_string())
+                    }
+                    Err(_) => {
+                        return Err(ParseError::Include {
+                            token: token.to_string(),
+                        });
+                    }
+                }
+            }
+            includes.push(include);
+        } else if let Some(include) = includes.last_mut() {
+            include.code.push_str(line);
+            include.code.push('\n');
+        }
+    }
+    Ok(includes)
+}
+
+impl FromStr for Section {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(Self::Header),
+            "behaviors" => Ok(Self::Behaviors),
+            "root" => Ok(Self::Root),
+            "footer" => Ok(Self::Footer),
+            _ => Err(format!("Invalid section: {s}")),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Mods {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: &str = serde::Deserialize::deserialize(deserializer)?;
+        s.split('+')
+            .map(|modifier| serde_yaml::from_str::<Modifier>(modifier.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Mods)
+            .map_err(|_err| serde::de::Error::custom(format!("Invalid modifiers: {s}")))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ComboKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ComboKeyVisitor;
+        impl<'de> serde::de::Visitor<'de> for ComboKeyVisitor {
+            type Value = ComboKey;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a glyph, Pos(n), or [row, column]")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                if let Some(position) = s.strip_prefix("Pos(").and_then(|s| s.strip_suffix(")")) {
+                    return position
+                        .trim()
+                        .parse()
+                        .map(ComboKey::Position)
+                        .map_err(|_| E::custom(format!("Invalid combo key: {s}")));
+                }
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => Ok(ComboKey::Glyph(ch)),
+                    _ => Err(E::custom(format!("Invalid combo key: {s}"))),
+                }
+            }
+
+            // Unquoted digits are parsed as numbers, but they're still glyphs.
+            fn visit_u64<E: serde::de::Error>(self, n: u64) -> Result<Self::Value, E> {
+                self.visit_str(&n.to_string())
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let err = || serde::de::Error::custom("Expected [row, column] for a combo key");
+                let row = seq.next_element()?.ok_or_else(err)?;
+                let col = seq.next_element()?.ok_or_else(err)?;
+                if seq.next_element::<usize>()?.is_some() {
+                    return Err(err());
+                }
+                Ok(ComboKey::Coordinates(row, col))
+            }
+        }
+        deserializer.deserialize_any(ComboKeyVisitor)
     }
-    keymap
 }
 
 // FIXME: Hacky